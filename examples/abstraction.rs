@@ -8,8 +8,8 @@ struct MyCard {
 
 impl MyCard {
     fn template() -> Arc<Template> {
-        Arc::new(Template::new(
-            3,
+        Arc::new(Template::from_name(
+            "my-project/my-model",
             "reanki-template".to_string(),
             "q: {{some_field}}".to_string(),
             "a: {{another_field}}".to_string(),
@@ -41,9 +41,13 @@ impl MyCard {
 
 fn main() {
     // create deck, model(s), template(s)
-    let mut deck = Deck::new(1, "My deck".to_string(), "My reanki deck".to_string());
-    let model = Arc::new(Model::new(
-        2,
+    let mut deck = Deck::from_name(
+        "my-project",
+        "My deck".to_string(),
+        "My reanki deck".to_string(),
+    );
+    let model = Arc::new(Model::from_name(
+        "my-project",
         "My model 2".to_string(),
         MyCard::fields(),
         0,
@@ -0,0 +1,41 @@
+use reanki::{Deck, Field, Model, ModelType, Note, Template, ankiconnect::AnkiConnect};
+use std::{collections::HashMap, sync::Arc};
+
+fn main() -> Result<(), reanki::Error> {
+    let model = Arc::new(Model::from_name(
+        "my-project",
+        "My model 1".to_string(),
+        vec![
+            Field::new("question-field".to_string()),
+            Field::new("answer-field".to_string()),
+        ],
+        0,
+        String::new(),
+        ModelType::Standard,
+    ));
+    let template = Arc::new(Template::from_name(
+        "my-project/my-model",
+        "reanki-template".to_string(),
+        "<div>{{question-field}}</div>".to_string(),
+        "<div id=answer>{{answer-field}}</div>".to_string(),
+    ));
+    let mut deck = Deck::from_name(
+        "my-project",
+        "My deck".to_string(),
+        "My reanki deck".to_string(),
+    );
+    deck.add_note(Note::new(
+        "my-note-4".to_string(),
+        model,
+        vec![template],
+        vec!["question-value".to_string(), "answer-value".to_string()],
+    ));
+
+    // requires Anki to be running with the AnkiConnect add-on installed
+    let report = AnkiConnect::new().sync(&deck, &HashMap::new())?;
+    println!(
+        "added {}, updated {}, unchanged {}",
+        report.added, report.updated, report.unchanged
+    );
+    Ok(())
+}
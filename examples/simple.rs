@@ -10,8 +10,8 @@ fn main() -> Result<(), reanki::Error> {
 "
     .to_string();
     // A model defines what fields our cards will have.
-    let model = Arc::new(Model::new(
-        1,
+    let model = Arc::new(Model::from_name(
+        "my-project",
         "My model 1".to_string(),
         vec![
             Field::new("question-field".to_string()),
@@ -22,8 +22,8 @@ fn main() -> Result<(), reanki::Error> {
         ModelType::Standard,
     ));
     // A template defines how those fields are displayed.
-    let template = Arc::new(Template::new(
-        2,
+    let template = Arc::new(Template::from_name(
+        "my-project/my-model",
         "reanki-template".to_string(),
         "<div>{{question-field}}</div>".to_string(),
         "<div id=answer>{{answer-field}}</div>".to_string(),
@@ -31,7 +31,11 @@ fn main() -> Result<(), reanki::Error> {
 
     // Create a deck and notes.
     // A deck is just a collection of notes.
-    let mut deck = Deck::new(3, "My deck".to_string(), "My reanki deck".to_string());
+    let mut deck = Deck::from_name(
+        "my-project",
+        "My deck".to_string(),
+        "My reanki deck".to_string(),
+    );
     // A note fills in the fields in our model with some information.
     // A card is a note that has been applied to a template.
     // For example, if we had two templates here, we would end up with two cards for this one note.
@@ -0,0 +1,555 @@
+//! Syncing decks into a running Anki with the [AnkiConnect](https://ankiweb.net/shared/info/2055492159) add-on.
+
+use crate::{Deck, Error, Model, Note, error};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+// the AnkiConnect API version this client is written against
+const API_VERSION: i64 = 6;
+
+/// A client for the AnkiConnect HTTP API.
+#[derive(Debug, Clone)]
+pub struct AnkiConnect {
+    url: String,
+    key: Option<String>,
+}
+
+/// The result of syncing a deck with [`AnkiConnect::sync`].
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The id of each synced note in Anki by guid.
+    /// Passing these to the next sync lets notes be matched even if their sort field has changed.
+    pub note_ids: HashMap<String, i64>,
+    /// The number of notes that were added.
+    pub added: usize,
+    /// The number of existing notes whose fields or tags were updated.
+    pub updated: usize,
+    /// The number of existing notes that were already up to date.
+    pub unchanged: usize,
+    /// The number of media files that were uploaded.
+    pub media: usize,
+}
+
+// a note as returned by notesInfo
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NoteInfo {
+    note_id: i64,
+    model_name: String,
+    tags: Vec<String>,
+    fields: HashMap<String, FieldInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldInfo {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    result: Value,
+    error: Option<String>,
+}
+
+impl Default for AnkiConnect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnkiConnect {
+    /// The address AnkiConnect listens on by default.
+    pub const DEFAULT_URL: &str = "http://127.0.0.1:8765";
+
+    /// Creates a new client for the default AnkiConnect address.
+    pub fn new() -> Self {
+        Self {
+            url: Self::DEFAULT_URL.to_string(),
+            key: None,
+        }
+    }
+
+    /// Set the address of the AnkiConnect endpoint.
+    pub fn url(mut self, url: String) -> Self {
+        self.url = url;
+        self
+    }
+
+    /// Set the API key, required if AnkiConnect has been configured with one.
+    pub fn key(mut self, key: String) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Sync the deck into Anki.
+    /// The deck and its models are created if they are missing and the deck's media files are uploaded.
+    /// Notes that already exist in the deck are updated if their fields or tags have changed, and other notes are added.
+    ///
+    /// A note is matched to an existing one by its guid through `note_ids`, which should contain the ids from an earlier [`SyncReport`],
+    /// and otherwise by its model and sort field value.
+    /// Note that Anki generates a card for each template of the model with a non-empty front,
+    /// regardless of the templates given to [`Note::new`].
+    pub fn sync(&self, deck: &Deck, note_ids: &HashMap<String, i64>) -> Result<SyncReport, Error> {
+        let mut report = SyncReport::default();
+
+        self.request("createDeck", serde_json::json!({ "deck": deck.name }))?;
+
+        let model_names = self.request("modelNames", serde_json::json!({}))?;
+        let model_names = serde_json::from_value::<HashSet<String>>(model_names)
+            .map_err(|_| unexpected_result("modelNames"))?;
+        for (model, templates) in deck.model_to_templates.values() {
+            if !model_names.contains(&model.name) {
+                let mut templates = templates.values().collect::<Vec<_>>();
+                templates.sort_by_key(|(ord, _t)| *ord);
+                let templates = templates
+                    .into_iter()
+                    .map(|(_ord, t)| {
+                        serde_json::json!({
+                            "Name": t.name,
+                            "Front": t.qfmt,
+                            "Back": t.afmt,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                self.request(
+                    "createModel",
+                    serde_json::json!({
+                        "modelName": model.name,
+                        "inOrderFields": model.fields.iter().map(|f| &f.name).collect::<Vec<_>>(),
+                        "css": model.full_css(),
+                        "isCloze": false,
+                        "cardTemplates": templates,
+                    }),
+                )?;
+            }
+        }
+
+        let assets = deck
+            .model_to_templates
+            .values()
+            .flat_map(|(model, _templates)| &model.assets);
+        for media in deck.media.iter().chain(assets) {
+            self.request(
+                "storeMediaFile",
+                serde_json::json!({
+                    "filename": media.name,
+                    "data": STANDARD.encode(&media.data),
+                }),
+            )?;
+            report.media += 1;
+        }
+
+        // fetch the notes that are already in the deck
+        let existing_ids = self.request(
+            "findNotes",
+            serde_json::json!({ "query": format!("\"deck:{}\"", escape_search(&deck.name)) }),
+        )?;
+        let existing = self.request("notesInfo", serde_json::json!({ "notes": existing_ids }))?;
+        let existing = serde_json::from_value::<Vec<NoteInfo>>(existing)
+            .map_err(|_| unexpected_result("notesInfo"))?;
+        let by_id = existing
+            .iter()
+            .map(|n| (n.note_id, n))
+            .collect::<HashMap<_, _>>();
+        let by_sort_field = existing
+            .iter()
+            .filter_map(|n| {
+                let model = deck
+                    .model_to_templates
+                    .values()
+                    .find(|(m, _)| m.name == n.model_name)?;
+                let sort_field = sort_field_name(&model.0)?;
+                let value = &n.fields.get(sort_field)?.value;
+                Some(((n.model_name.as_str(), value.as_str()), n))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut new_notes = Vec::new();
+        for note in &deck.notes {
+            let existing = note_ids
+                .get(&note.guid)
+                .and_then(|id| by_id.get(id))
+                .or_else(|| {
+                    let sort_field = note
+                        .field_values
+                        .get(usize::try_from(note.model.sort_field).ok()?)?;
+                    by_sort_field.get(&(note.model.name.as_str(), sort_field.as_str()))
+                });
+            match existing {
+                Some(existing) => {
+                    report.note_ids.insert(note.guid.clone(), existing.note_id);
+                    if is_up_to_date(note, existing) {
+                        report.unchanged += 1;
+                    } else {
+                        self.request(
+                            "updateNote",
+                            serde_json::json!({
+                                "note": {
+                                    "id": existing.note_id,
+                                    "fields": fields_json(note),
+                                    "tags": note.tags.as_deref().unwrap_or_default(),
+                                }
+                            }),
+                        )?;
+                        report.updated += 1;
+                    }
+                }
+                None => new_notes.push(note),
+            }
+        }
+
+        if !new_notes.is_empty() {
+            let notes = new_notes
+                .iter()
+                .map(|note| {
+                    serde_json::json!({
+                        "deckName": deck.name,
+                        "modelName": note.model.name,
+                        "fields": fields_json(note),
+                        "tags": note.tags.as_deref().unwrap_or_default(),
+                        "options": { "allowDuplicate": true },
+                    })
+                })
+                .collect::<Vec<_>>();
+            let added = self.request("addNotes", serde_json::json!({ "notes": notes }))?;
+            let added = serde_json::from_value::<Vec<Option<i64>>>(added)
+                .map_err(|_| unexpected_result("addNotes"))?;
+            for (note, id) in new_notes.iter().zip(added) {
+                let id = id.ok_or_else(|| Error::AnkiConnect {
+                    message: format!("Failed to add note {}", note.guid),
+                })?;
+                report.note_ids.insert(note.guid.clone(), id);
+                report.added += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    // sends a single action and returns its result
+    fn request(&self, action: &str, params: Value) -> Result<Value, Error> {
+        let mut body = serde_json::json!({
+            "action": action,
+            "version": API_VERSION,
+            "params": params,
+        });
+        if let Some(key) = &self.key {
+            body["key"] = Value::String(key.clone());
+        }
+
+        tracing::debug!("Sending AnkiConnect action {action}");
+        let response = ureq::post(&self.url)
+            .send_json(&body)
+            .map_err(error!(Error::Http, "Failed to send request to AnkiConnect"))?
+            .body_mut()
+            .read_json::<Response>()
+            .map_err(error!(
+                Error::Http,
+                "Failed to read response from AnkiConnect"
+            ))?;
+        match response.error {
+            Some(error) => Err(Error::AnkiConnect {
+                message: format!("{action} failed: {error}"),
+            }),
+            None => Ok(response.result),
+        }
+    }
+}
+
+// the name of the model's sort field
+fn sort_field_name(model: &Model) -> Option<&str> {
+    let sort_field = usize::try_from(model.sort_field).ok()?;
+    model.fields.get(sort_field).map(|f| f.name.as_str())
+}
+
+// the note's fields as a field name => value object
+fn fields_json(note: &Note) -> Value {
+    let fields = note
+        .model
+        .fields
+        .iter()
+        .zip(&note.field_values)
+        .map(|(field, value)| (field.name.clone(), Value::String(value.clone())))
+        .collect::<Map<_, _>>();
+    Value::Object(fields)
+}
+
+// checks whether the existing note already has the note's fields and tags
+fn is_up_to_date(note: &Note, existing: &NoteInfo) -> bool {
+    let fields_match = note
+        .model
+        .fields
+        .iter()
+        .zip(&note.field_values)
+        .all(|(field, value)| {
+            existing
+                .fields
+                .get(&field.name)
+                .is_some_and(|f| &f.value == value)
+        });
+    let tags = note.tags.as_deref().unwrap_or_default();
+    let tags_match =
+        tags.len() == existing.tags.len() && tags.iter().all(|tag| existing.tags.contains(tag));
+    fields_match && tags_match
+}
+
+// escapes the characters that have a special meaning inside a quoted search term
+fn escape_search(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unexpected_result(action: &str) -> Error {
+    Error::AnkiConnect {
+        message: format!("Unexpected result for {action}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Field, ModelType, Template};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+    };
+
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    // serves canned responses for the actions on a local port, and records the requests it received
+    fn mock_server(respond: fn(&str, &Value) -> Value) -> (AnkiConnect, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let recorded = recorded.clone();
+                std::thread::spawn(move || serve(stream, respond, &recorded));
+            }
+        });
+        (AnkiConnect::new().url(url), requests)
+    }
+
+    // answers requests on the connection until the client closes it
+    fn serve(stream: TcpStream, respond: fn(&str, &Value) -> Value, requests: &Requests) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let request = serde_json::from_slice::<Value>(&body).unwrap();
+            let action = request["action"].as_str().unwrap().to_string();
+            let response = respond(&action, &request["params"]).to_string();
+            requests
+                .lock()
+                .unwrap()
+                .push((action, request["params"].clone()));
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+        }
+    }
+
+    fn ok(result: Value) -> Value {
+        serde_json::json!({ "result": result, "error": null })
+    }
+
+    fn deck() -> Deck {
+        let model = Arc::new(Model::new(
+            1,
+            "Basic".to_string(),
+            vec![
+                Field::new("Front".to_string()),
+                Field::new("Back".to_string()),
+            ],
+            0,
+            String::new(),
+            ModelType::Standard,
+        ));
+        let template = Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            "{{Front}}".to_string(),
+            "{{Back}}".to_string(),
+        ));
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        for (guid, front, back) in [
+            ("by_guid", "renamed", "back"),
+            ("by_sort_field", "sort", "new back"),
+            ("unchanged", "same", "back"),
+            ("new", "new", "back"),
+        ] {
+            deck.add_note(Note::new(
+                guid.to_string(),
+                model.clone(),
+                vec![template.clone()],
+                vec![front.to_string(), back.to_string()],
+            ));
+        }
+        deck
+    }
+
+    fn actions(requests: &Requests) -> Vec<String> {
+        let requests = requests.lock().unwrap();
+        requests.iter().map(|(action, _)| action.clone()).collect()
+    }
+
+    fn params(requests: &Requests, action: &str) -> Vec<Value> {
+        let requests = requests.lock().unwrap();
+        requests
+            .iter()
+            .filter(|(a, _)| a == action)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    #[test]
+    fn creates_the_deck_and_model_and_adds_notes() {
+        let (client, requests) = mock_server(|action, params| match action {
+            "modelNames" | "findNotes" | "notesInfo" => ok(serde_json::json!([])),
+            "addNotes" => {
+                let count = params["notes"].as_array().unwrap().len();
+                ok((0..count).map(|i| 100 + i).collect())
+            }
+            _ => ok(Value::Null),
+        });
+        let mut deck = deck();
+        deck.add_media("sound.mp3".to_string(), b"sound".to_vec());
+
+        let report = client.sync(&deck, &HashMap::new()).unwrap();
+
+        assert_eq!(
+            actions(&requests),
+            [
+                "createDeck",
+                "modelNames",
+                "createModel",
+                "storeMediaFile",
+                "findNotes",
+                "notesInfo",
+                "addNotes"
+            ]
+        );
+        assert_eq!(params(&requests, "createDeck")[0]["deck"], "Deck");
+        let model = &params(&requests, "createModel")[0];
+        assert_eq!(model["modelName"], "Basic");
+        assert_eq!(model["inOrderFields"], serde_json::json!(["Front", "Back"]));
+        assert_eq!(model["cardTemplates"][0]["Front"], "{{Front}}");
+        let media = &params(&requests, "storeMediaFile")[0];
+        assert_eq!(media["filename"], "sound.mp3");
+        assert_eq!(media["data"], STANDARD.encode(b"sound"));
+        let notes = &params(&requests, "addNotes")[0]["notes"];
+        assert_eq!(notes.as_array().unwrap().len(), 4);
+        assert_eq!(notes[0]["deckName"], "Deck");
+        assert_eq!(notes[0]["fields"]["Front"], "renamed");
+
+        assert_eq!((report.added, report.updated, report.unchanged), (4, 0, 0));
+        assert_eq!(report.media, 1);
+        assert_eq!(report.note_ids["new"], 103);
+    }
+
+    #[test]
+    fn updates_notes_matched_by_guid_or_sort_field() {
+        let (client, requests) = mock_server(|action, _params| match action {
+            "modelNames" => ok(serde_json::json!(["Basic"])),
+            "findNotes" => ok(serde_json::json!([1, 2, 3])),
+            "notesInfo" => {
+                let note = |id: i64, front: &str, back: &str| {
+                    serde_json::json!({
+                        "noteId": id,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {
+                            "Front": { "value": front, "order": 0 },
+                            "Back": { "value": back, "order": 1 },
+                        },
+                    })
+                };
+                ok(serde_json::json!([
+                    note(1, "old", "back"),
+                    note(2, "sort", "back"),
+                    note(3, "same", "back"),
+                ]))
+            }
+            "addNotes" => ok(serde_json::json!([4])),
+            _ => ok(Value::Null),
+        });
+        let note_ids = HashMap::from([("by_guid".to_string(), 1)]);
+
+        let report = client.sync(&deck(), &note_ids).unwrap();
+
+        assert!(!actions(&requests).contains(&"createModel".to_string()));
+        let updates = params(&requests, "updateNote")
+            .into_iter()
+            .map(|params| params["note"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0]["id"], 1);
+        assert_eq!(updates[0]["fields"]["Front"], "renamed");
+        assert_eq!(updates[1]["id"], 2);
+        assert_eq!(updates[1]["fields"]["Back"], "new back");
+        assert_eq!((report.added, report.updated, report.unchanged), (1, 2, 1));
+        assert_eq!(
+            report.note_ids,
+            HashMap::from([
+                ("by_guid".to_string(), 1),
+                ("by_sort_field".to_string(), 2),
+                ("unchanged".to_string(), 3),
+                ("new".to_string(), 4),
+            ])
+        );
+    }
+
+    #[test]
+    fn error_responses_become_errors() {
+        let (client, requests) = mock_server(|action, _params| match action {
+            "createDeck" => {
+                serde_json::json!({ "result": null, "error": "collection is not available" })
+            }
+            _ => ok(Value::Null),
+        });
+
+        let err = client.sync(&deck(), &HashMap::new()).unwrap_err();
+
+        assert!(
+            matches!(&err, Error::AnkiConnect { message } if message == "createDeck failed: collection is not available"),
+            "{err}"
+        );
+        assert_eq!(actions(&requests), ["createDeck"]);
+    }
+
+    #[test]
+    fn escapes_searches() {
+        assert_eq!(escape_search(r#"a"b\c*d_e"#), r#"a\"b\\c\*d\_e"#);
+    }
+}
@@ -0,0 +1,34 @@
+//! Prints a changelog between two builds of a deck.
+//! Usage: reanki-diff <old.apkg> <new.apkg>
+
+use reanki::diff::{self, Snapshot};
+use std::{fs::File, process::ExitCode};
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let [old, new] = args.as_slice() else {
+        eprintln!("Usage: reanki-diff <old.apkg> <new.apkg>");
+        return ExitCode::FAILURE;
+    };
+
+    match run(old, new) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(old: &str, new: &str) -> Result<(), reanki::Error> {
+    let read = |path: &str| {
+        let file = File::open(path).map_err(|source| reanki::Error::Io {
+            message: "Failed to open package",
+            source,
+        })?;
+        Snapshot::read_apkg(file)
+    };
+    let diff = diff::diff(&read(old)?, &read(new)?);
+    print!("{diff}");
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use crate::{
+    BuildState, Contents, Deck, Error, FilteredDeck, MediaReport, PackageKind, WriteOptions, media,
+};
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    io::{Seek, Write},
+};
+
+/// A full Anki collection with any number of decks.
+/// It can be written as a colpkg file which replaces the user's whole collection when imported,
+/// or as an apkg file which adds all of its decks to the user's collection.
+#[derive(Debug, Default)]
+pub struct Collection {
+    pub(crate) decks: Vec<Deck>,
+    pub(crate) filtered_decks: Vec<FilteredDeck>,
+    pub(crate) config: CollectionConfig,
+}
+
+impl Collection {
+    /// Creates a new empty collection. The default deck is always included in colpkg files.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the collection-wide settings, which take effect when the collection is imported from a colpkg file.
+    pub fn config(mut self, config: CollectionConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Add a deck to the collection.
+    pub fn add_deck(&mut self, deck: Deck) {
+        self.decks.push(deck);
+    }
+
+    /// Add a filtered deck to the collection. It is written alongside the normal decks and starts empty,
+    /// Anki fills it with the matching cards when the user rebuilds it.
+    pub fn add_filtered_deck(&mut self, filtered_deck: FilteredDeck) {
+        self.filtered_decks.push(filtered_deck);
+    }
+
+    /// Gathers the media files of all the decks in the collection like when it is written and summarises them.
+    /// Files with the same content are only stored once, even if they are in different decks.
+    pub fn media_report(&self, options: &WriteOptions) -> Result<MediaReport, Error> {
+        media::collect(&self.decks.iter().collect::<Vec<_>>(), options).map(|media| media.report())
+    }
+
+    /// Write the collection into the writer in the colpkg format.
+    pub fn write_colpkg<W: Write + Seek>(&self, writer: W) -> Result<(), Error> {
+        self.write_colpkg_with_options(writer, &WriteOptions::default())
+    }
+
+    /// Write the collection into the writer in the colpkg format with the given options.
+    pub fn write_colpkg_with_options<W: Write + Seek>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        crate::write_package(&self.contents(), PackageKind::Colpkg, writer, options, None)
+    }
+
+    /// Write all the decks in the collection into the writer in the apkg format.
+    pub fn write_apkg<W: Write + Seek>(&self, writer: W) -> Result<(), Error> {
+        self.write_apkg_with_options(writer, &WriteOptions::default())
+    }
+
+    /// Write all the decks in the collection into the writer in the apkg format with the given options.
+    pub fn write_apkg_with_options<W: Write + Seek>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        crate::write_package(&self.contents(), PackageKind::Apkg, writer, options, None)
+    }
+
+    /// Write all the decks in the collection into the writer in the apkg format as an update to the build recorded in `state`.
+    /// See [`Deck::write_update`].
+    pub fn write_apkg_update<W: Write + Seek>(
+        &self,
+        writer: W,
+        state: &mut BuildState,
+    ) -> Result<(), Error> {
+        self.write_apkg_update_with_options(writer, state, &WriteOptions::default())
+    }
+
+    /// Write all the decks in the collection into the writer in the apkg format as an update to the build recorded in `state`
+    /// with the given options.
+    pub fn write_apkg_update_with_options<W: Write + Seek>(
+        &self,
+        writer: W,
+        state: &mut BuildState,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        crate::write_package(
+            &self.contents(),
+            PackageKind::Apkg,
+            writer,
+            options,
+            Some(state),
+        )
+    }
+
+    fn contents(&self) -> Contents<'_> {
+        Contents {
+            decks: self.decks.iter().collect(),
+            filtered_decks: &self.filtered_decks,
+            config: Cow::Borrowed(&self.config),
+        }
+    }
+}
+
+/// When new cards are shown during study, see [`CollectionConfig::new_spread`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NewSpread {
+    /// Mixed in with the reviews.
+    #[default]
+    MixWithReviews,
+    /// After the reviews.
+    AfterReviews,
+    /// Before the reviews.
+    BeforeReviews,
+}
+
+impl NewSpread {
+    fn to_anki(self) -> i64 {
+        match self {
+            Self::MixWithReviews => 0,
+            Self::AfterReviews => 1,
+            Self::BeforeReviews => 2,
+        }
+    }
+}
+
+/// The column the card browser is sorted by, see [`CollectionConfig::browser_sort`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrowserSort {
+    /// The note's sort field.
+    #[default]
+    SortField,
+    /// When the note was created.
+    Created,
+    /// When the note was last modified.
+    NoteModified,
+    /// When the card was last modified.
+    CardModified,
+    /// When the card is due.
+    Due,
+    /// The card's interval.
+    Interval,
+    /// The card's ease.
+    Ease,
+    /// The number of times the card has been reviewed.
+    Reviews,
+    /// The number of times the card has been forgotten.
+    Lapses,
+}
+
+impl BrowserSort {
+    fn to_anki(self) -> &'static str {
+        match self {
+            Self::SortField => "noteFld",
+            Self::Created => "noteCrt",
+            Self::NoteModified => "noteMod",
+            Self::CardModified => "cardMod",
+            Self::Due => "cardDue",
+            Self::Interval => "cardIvl",
+            Self::Ease => "cardEase",
+            Self::Reviews => "cardReps",
+            Self::Lapses => "cardLapses",
+        }
+    }
+}
+
+/// Collection-wide settings, written into colpkg files so that the collection behaves as configured from the first launch.
+/// Deck packages are written with Anki's defaults, which Anki ignores when importing them.
+#[derive(Debug, Clone)]
+pub struct CollectionConfig {
+    current_deck: i64,
+    current_model: Option<i64>,
+    next_position: Option<i64>,
+    new_spread: NewSpread,
+    learn_ahead_secs: u32,
+    timebox_secs: u32,
+    show_next_review_time: bool,
+    show_remaining_count: bool,
+    browser_sort: BrowserSort,
+    browser_sort_backwards: bool,
+    add_to_current_deck: bool,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            current_deck: 1,
+            current_model: None,
+            next_position: None,
+            new_spread: NewSpread::MixWithReviews,
+            learn_ahead_secs: 1200,
+            timebox_secs: 0,
+            show_next_review_time: true,
+            show_remaining_count: true,
+            browser_sort: BrowserSort::SortField,
+            browser_sort_backwards: false,
+            add_to_current_deck: true,
+        }
+    }
+}
+
+impl CollectionConfig {
+    /// Creates a new CollectionConfig with Anki's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the deck that is selected when Anki is opened. The default deck with the id 1 is selected by default.
+    pub fn current_deck(mut self, deck_id: i64) -> Self {
+        self.current_deck = deck_id;
+        self
+    }
+
+    /// Set the note type that is selected when adding notes.
+    pub fn current_model(mut self, model_id: i64) -> Self {
+        self.current_model = Some(model_id);
+        self
+    }
+
+    /// Set the position given to the next new card that is added.
+    /// Defaults to the position after the last new card in the collection, see [`crate::Note::order`].
+    pub fn next_position(mut self, next_position: i64) -> Self {
+        self.next_position = Some(next_position);
+        self
+    }
+
+    /// Set when new cards are shown during study.
+    pub fn new_spread(mut self, new_spread: NewSpread) -> Self {
+        self.new_spread = new_spread;
+        self
+    }
+
+    /// Set how many seconds early learning cards can be shown when there is nothing else to study. Defaults to 20 minutes.
+    pub fn learn_ahead_secs(mut self, learn_ahead_secs: u32) -> Self {
+        self.learn_ahead_secs = learn_ahead_secs;
+        self
+    }
+
+    /// Set the number of seconds after which Anki shows how much has been studied, 0 to never show it. Disabled by default.
+    pub fn timebox_secs(mut self, timebox_secs: u32) -> Self {
+        self.timebox_secs = timebox_secs;
+        self
+    }
+
+    /// Set whether the next review time is shown above the answer buttons. Enabled by default.
+    pub fn show_next_review_time(mut self, show_next_review_time: bool) -> Self {
+        self.show_next_review_time = show_next_review_time;
+        self
+    }
+
+    /// Set whether the number of remaining cards is shown during study. Enabled by default.
+    pub fn show_remaining_count(mut self, show_remaining_count: bool) -> Self {
+        self.show_remaining_count = show_remaining_count;
+        self
+    }
+
+    /// Set the column the card browser is sorted by and whether it's sorted in descending order.
+    pub fn browser_sort(mut self, browser_sort: BrowserSort, backwards: bool) -> Self {
+        self.browser_sort = browser_sort;
+        self.browser_sort_backwards = backwards;
+        self
+    }
+
+    /// Set whether notes are added to the current deck by default instead of the deck last used with the note type.
+    /// Enabled by default.
+    pub fn add_to_current_deck(mut self, add_to_current_deck: bool) -> Self {
+        self.add_to_current_deck = add_to_current_deck;
+        self
+    }
+
+    pub(crate) fn to_anki_json(&self, decks: &[&Deck], filtered_decks: &[FilteredDeck]) -> Value {
+        // the current deck and its subdecks are the ones that are studied when it's selected
+        let names = decks
+            .iter()
+            .map(|d| (d.id, &d.name))
+            .chain(filtered_decks.iter().map(|d| (d.id, &d.name)));
+        let mut active_decks = vec![self.current_deck];
+        if let Some((_id, current_name)) = names.clone().find(|(id, _)| *id == self.current_deck) {
+            let prefix = format!("{current_name}::");
+            active_decks.extend(
+                names
+                    .filter(|(_id, name)| name.starts_with(&prefix))
+                    .map(|(id, _name)| id),
+            );
+        }
+        let next_position = self.next_position.unwrap_or_else(|| {
+            decks
+                .iter()
+                .flat_map(|d| &d.notes)
+                .map(|n| i64::from(n.card_ord) + 1)
+                .max()
+                .unwrap_or(1)
+        });
+
+        serde_json::json!({
+            // the selected deck
+            "curDeck": self.current_deck,
+            // the selected deck and its subdecks
+            "activeDecks": active_decks,
+            // the selected note type, null for the first one
+            "curModel": self.current_model,
+            // the due position of the next new card
+            "nextPos": next_position,
+            // new cards mixed with reviews = 0, after reviews = 1, before reviews = 2
+            "newSpread": self.new_spread.to_anki(),
+            // learn ahead limit in seconds
+            "collapseTime": self.learn_ahead_secs,
+            // timebox in seconds, 0 = disabled
+            "timeLim": self.timebox_secs,
+            // show the next review time above the answer buttons
+            "estTimes": self.show_next_review_time,
+            // show the remaining card count
+            "dueCounts": self.show_remaining_count,
+            // browser sort column
+            "sortType": self.browser_sort.to_anki(),
+            // browser sort direction
+            "sortBackwards": self.browser_sort_backwards,
+            // add notes to the current deck instead of the note type's last deck
+            "addToCur": self.add_to_current_deck,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Note, schema,
+        tests::{model, open_collection, template},
+    };
+    use diesel::prelude::*;
+    use std::io::Cursor;
+
+    fn note(guid: &str, order: u16) -> Note {
+        Note::new(
+            guid.to_string(),
+            model(),
+            vec![template()],
+            vec![guid.to_string(), String::new()],
+        )
+        .order(order)
+    }
+
+    // the conf JSON in the col table of the collection written as a colpkg
+    fn written_conf(collection: &Collection) -> Value {
+        let mut package = Cursor::new(Vec::new());
+        collection.write_colpkg(&mut package).unwrap();
+        let mut conn = open_collection(package.into_inner());
+        let conf = schema::col::table
+            .select(schema::col::conf)
+            .first::<String>(&mut conn)
+            .unwrap();
+        serde_json::from_str(&conf).unwrap()
+    }
+
+    #[test]
+    fn writes_the_config_into_colpkg_files() {
+        let mut course = Deck::new(10, "Course".to_string(), String::new());
+        course.add_note(note("a", 3));
+        let mut chapter = Deck::new(11, "Course::Chapter 1".to_string(), String::new());
+        chapter.add_note(note("b", 7));
+        let mut collection = Collection::new().config(
+            CollectionConfig::new()
+                .current_deck(10)
+                .new_spread(NewSpread::AfterReviews)
+                .browser_sort(BrowserSort::Due, true),
+        );
+        collection.add_deck(course);
+        collection.add_deck(chapter);
+        collection.add_deck(Deck::new(12, "Other".to_string(), String::new()));
+        collection.add_filtered_deck(FilteredDeck::new(
+            13,
+            "Course::Review".to_string(),
+            "is:due".to_string(),
+        ));
+
+        let conf = written_conf(&collection);
+        assert_eq!(conf["curDeck"], 10);
+        assert_eq!(conf["activeDecks"], serde_json::json!([10, 11, 13]));
+        assert_eq!(conf["nextPos"], 8);
+        assert_eq!(conf["newSpread"], 1);
+        assert_eq!(conf["sortType"], "cardDue");
+        assert_eq!(conf["sortBackwards"], true);
+        assert_eq!(conf["curModel"], Value::Null);
+
+        let conf =
+            written_conf(&Collection::new().config(CollectionConfig::new().next_position(100)));
+        assert_eq!(conf["curDeck"], 1);
+        assert_eq!(conf["activeDecks"], serde_json::json!([1]));
+        assert_eq!(conf["nextPos"], 100);
+        assert_eq!(conf["sortType"], "noteFld");
+        assert_eq!(conf["sortBackwards"], false);
+    }
+}
@@ -0,0 +1,714 @@
+//! Comparing two builds of a deck.
+
+use crate::{Collection, Deck, Error, WriteOptions, error, media, schema};
+use diesel::{Connection, SqliteConnection, prelude::*};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    io::{Read, Seek},
+};
+use zip::ZipArchive;
+
+/// The notes and models of a deck build, which can be compared with [`diff`].
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    // guid => note
+    notes: BTreeMap<String, NoteSnapshot>,
+    // model id => model
+    models: BTreeMap<i64, ModelSnapshot>,
+}
+
+#[derive(Debug)]
+struct NoteSnapshot {
+    model_id: i64,
+    fields: Vec<String>,
+    tags: BTreeSet<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ModelSnapshot {
+    name: String,
+    fields: Vec<String>,
+    sort_field: usize,
+    templates: Vec<TemplateSnapshot>,
+    css: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+struct TemplateSnapshot {
+    name: String,
+    qfmt: String,
+    afmt: String,
+}
+
+// the parts of the model JSON in the col table that are compared
+#[derive(Debug, Deserialize)]
+struct ModelJson {
+    name: String,
+    flds: Vec<FieldJson>,
+    sortf: usize,
+    tmpls: Vec<TemplateSnapshot>,
+    css: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldJson {
+    name: String,
+}
+
+impl Snapshot {
+    /// Creates a snapshot of the deck as it is written with the default options.
+    pub fn from_deck(deck: &Deck) -> Result<Self, Error> {
+        Self::from_deck_with_options(deck, &WriteOptions::default())
+    }
+
+    /// Creates a snapshot of the deck as it is written with the given options.
+    /// References to media files are rewritten like when the deck is written, so that the snapshot matches the written package.
+    pub fn from_deck_with_options(deck: &Deck, options: &WriteOptions) -> Result<Self, Error> {
+        Self::from_decks(&[deck], options)
+    }
+
+    /// Creates a snapshot of all the decks in the collection as they are written with the default options.
+    pub fn from_collection(collection: &Collection) -> Result<Self, Error> {
+        Self::from_collection_with_options(collection, &WriteOptions::default())
+    }
+
+    /// Creates a snapshot of all the decks in the collection as they are written with the given options.
+    pub fn from_collection_with_options(
+        collection: &Collection,
+        options: &WriteOptions,
+    ) -> Result<Self, Error> {
+        Self::from_decks(&collection.decks.iter().collect::<Vec<_>>(), options)
+    }
+
+    /// Reads a snapshot from an apkg or colpkg file.
+    /// Only files with the legacy collection format, like the ones written by reanki, are supported.
+    pub fn read_apkg<R: Read + Seek>(reader: R) -> Result<Self, Error> {
+        let mut zip =
+            ZipArchive::new(reader).map_err(error!(Error::Zip, "Failed to open zip archive"))?;
+        // newer versions of Anki name the legacy collection collection.anki21
+        let name = if zip.index_for_name("collection.anki21").is_some() {
+            "collection.anki21"
+        } else {
+            "collection.anki2"
+        };
+        let mut collection = zip.by_name(name).map_err(error!(
+            Error::Zip,
+            "Failed to find collection in zip archive"
+        ))?;
+        let mut buf = Vec::new();
+        collection.read_to_end(&mut buf).map_err(error!(
+            Error::Io,
+            "Failed to read collection from zip archive"
+        ))?;
+
+        let mut conn = SqliteConnection::establish(":memory:").map_err(error!(
+            Error::DieselConn,
+            "Failed to establish connection to in-memory sqlite database"
+        ))?;
+        conn.deserialize_readonly_database_from_buffer(&buf)
+            .map_err(error!(Error::Diesel, "Failed to load collection"))?;
+
+        let models = schema::col::table
+            .select(schema::col::models)
+            .first::<String>(&mut conn)
+            .map_err(error!(Error::Diesel, "Failed to query models"))?;
+        let models = serde_json::from_str::<HashMap<i64, ModelJson>>(&models)
+            .map_err(error!(Error::Json, "Failed to parse models"))?;
+        let notes = schema::notes::table
+            .select((
+                schema::notes::guid,
+                schema::notes::mid,
+                schema::notes::flds,
+                schema::notes::tags,
+            ))
+            .load::<(String, i64, String, String)>(&mut conn)
+            .map_err(error!(Error::Diesel, "Failed to query notes"))?;
+
+        let mut snapshot = Self::default();
+        for (id, model) in models {
+            snapshot.models.insert(
+                id,
+                ModelSnapshot {
+                    name: model.name,
+                    fields: model.flds.into_iter().map(|f| f.name).collect(),
+                    sort_field: model.sortf,
+                    templates: model.tmpls,
+                    css: model.css,
+                },
+            );
+        }
+        for (guid, model_id, fields, tags) in notes {
+            snapshot.notes.insert(
+                guid,
+                NoteSnapshot {
+                    model_id,
+                    fields: fields.split('\x1f').map(str::to_string).collect(),
+                    tags: tags.split_whitespace().map(str::to_string).collect(),
+                },
+            );
+        }
+        // the database borrows the buffer, so it can only be dropped after the queries
+        drop(conn);
+        drop(buf);
+        Ok(snapshot)
+    }
+
+    fn from_decks(decks: &[&Deck], options: &WriteOptions) -> Result<Self, Error> {
+        let media = media::collect(decks, options)?;
+        let mut snapshot = Self::default();
+        for deck in decks {
+            snapshot.add_deck(deck, &media);
+        }
+        Ok(snapshot)
+    }
+
+    fn add_deck(&mut self, deck: &Deck, media: &media::MediaFiles) {
+        for (model, templates) in deck.model_to_templates.values() {
+            let mut templates = templates.values().collect::<Vec<_>>();
+            templates.sort_by_key(|(ord, _t)| *ord);
+            self.models
+                .entry(model.id)
+                .or_insert_with(|| ModelSnapshot {
+                    name: model.name.clone(),
+                    fields: model.fields.iter().map(|f| f.name.clone()).collect(),
+                    sort_field: usize::try_from(model.sort_field).unwrap_or_default(),
+                    templates: templates
+                        .into_iter()
+                        .map(|(_ord, t)| TemplateSnapshot {
+                            name: t.name.clone(),
+                            qfmt: t.qfmt.clone(),
+                            afmt: t.afmt.clone(),
+                        })
+                        .collect(),
+                    css: media.rewrite_css(&model.full_css()).into_owned(),
+                });
+        }
+        for note in &deck.notes {
+            self.notes.insert(
+                note.guid.clone(),
+                NoteSnapshot {
+                    model_id: note.model.id,
+                    fields: note
+                        .field_values
+                        .iter()
+                        .map(|value| media.rewrite_field(value).into_owned())
+                        .collect(),
+                    tags: note.tags.iter().flatten().cloned().collect(),
+                },
+            );
+        }
+    }
+
+    // the value of the note's sort field, used to make the changelog readable
+    fn sort_field<'a>(&'a self, note: &'a NoteSnapshot) -> &'a str {
+        self.models
+            .get(&note.model_id)
+            .and_then(|m| note.fields.get(m.sort_field))
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn field_name(&self, model_id: i64, index: usize) -> String {
+        self.models
+            .get(&model_id)
+            .and_then(|m| m.fields.get(index))
+            .cloned()
+            .unwrap_or_else(|| format!("field {}", index + 1))
+    }
+}
+
+/// The differences between two deck builds.
+#[derive(Debug, Default)]
+pub struct DeckDiff {
+    /// Notes that were added, with the value of their sort field.
+    pub added: Vec<NoteSummary>,
+    /// Notes that were removed, with the value of their sort field.
+    pub removed: Vec<NoteSummary>,
+    /// Notes whose model, fields or tags changed.
+    pub modified: Vec<NoteChange>,
+    /// Models that were added, removed or changed.
+    pub models: Vec<ModelChange>,
+}
+
+/// A note that only exists in one of the builds.
+#[derive(Debug)]
+pub struct NoteSummary {
+    pub guid: String,
+    pub sort_field: String,
+}
+
+/// The changes to a note that exists in both builds.
+#[derive(Debug)]
+pub struct NoteChange {
+    pub guid: String,
+    /// Set if the note's model changed, as (old model id, new model id).
+    pub model: Option<(i64, i64)>,
+    pub fields: Vec<FieldChange>,
+    pub tags_added: Vec<String>,
+    pub tags_removed: Vec<String>,
+}
+
+/// A changed field value.
+#[derive(Debug)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The changes to a model.
+#[derive(Debug)]
+pub struct ModelChange {
+    pub id: i64,
+    pub name: String,
+    pub kind: ModelChangeKind,
+}
+
+/// The ways a model can change.
+#[derive(Debug)]
+pub enum ModelChangeKind {
+    Added,
+    Removed,
+    Renamed {
+        old: String,
+    },
+    /// Fields were added, removed, renamed or reordered.
+    Fields {
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    /// Templates were added, removed, renamed or reordered.
+    Templates {
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    /// The front or back of a template was edited.
+    TemplateEdited {
+        template: String,
+    },
+    SortField {
+        old: usize,
+        new: usize,
+    },
+    Css,
+}
+
+impl ModelChangeKind {
+    /// Whether the change modifies the model's schema, which forces users to do a full sync after importing the deck.
+    /// Only changes to the fields and templates themselves do, editing a template's content or the sort field doesn't.
+    pub fn requires_full_sync(&self) -> bool {
+        matches!(
+            self,
+            Self::Removed | Self::Fields { .. } | Self::Templates { .. }
+        )
+    }
+}
+
+impl DeckDiff {
+    /// Whether there are no differences between the builds.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.models.is_empty()
+    }
+
+    /// Whether any of the model changes force users to do a full sync.
+    pub fn requires_full_sync(&self) -> bool {
+        self.models.iter().any(|m| m.kind.requires_full_sync())
+    }
+}
+
+/// Compares two builds, with `old` being the earlier one. Notes are matched by their guid.
+pub fn diff(old: &Snapshot, new: &Snapshot) -> DeckDiff {
+    let mut diff = DeckDiff::default();
+
+    for (id, new_model) in &new.models {
+        let Some(old_model) = old.models.get(id) else {
+            diff.models.push(ModelChange {
+                id: *id,
+                name: new_model.name.clone(),
+                kind: ModelChangeKind::Added,
+            });
+            continue;
+        };
+        let mut push = |kind| {
+            diff.models.push(ModelChange {
+                id: *id,
+                name: new_model.name.clone(),
+                kind,
+            })
+        };
+        if old_model.name != new_model.name {
+            push(ModelChangeKind::Renamed {
+                old: old_model.name.clone(),
+            });
+        }
+        if old_model.fields != new_model.fields {
+            push(ModelChangeKind::Fields {
+                old: old_model.fields.clone(),
+                new: new_model.fields.clone(),
+            });
+        }
+        let old_templates = old_model.templates.iter().map(|t| &t.name);
+        let new_templates = new_model.templates.iter().map(|t| &t.name);
+        if !old_templates.clone().eq(new_templates.clone()) {
+            push(ModelChangeKind::Templates {
+                old: old_templates.cloned().collect(),
+                new: new_templates.cloned().collect(),
+            });
+        }
+        for new_template in &new_model.templates {
+            let edited = old_model
+                .templates
+                .iter()
+                .find(|t| t.name == new_template.name)
+                .is_some_and(|t| t != new_template);
+            if edited {
+                push(ModelChangeKind::TemplateEdited {
+                    template: new_template.name.clone(),
+                });
+            }
+        }
+        if old_model.sort_field != new_model.sort_field {
+            push(ModelChangeKind::SortField {
+                old: old_model.sort_field,
+                new: new_model.sort_field,
+            });
+        }
+        if old_model.css != new_model.css {
+            push(ModelChangeKind::Css);
+        }
+    }
+    for (id, old_model) in &old.models {
+        if !new.models.contains_key(id) {
+            diff.models.push(ModelChange {
+                id: *id,
+                name: old_model.name.clone(),
+                kind: ModelChangeKind::Removed,
+            });
+        }
+    }
+
+    for (guid, new_note) in &new.notes {
+        let Some(old_note) = old.notes.get(guid) else {
+            diff.added.push(NoteSummary {
+                guid: guid.clone(),
+                sort_field: new.sort_field(new_note).to_string(),
+            });
+            continue;
+        };
+
+        let field_count = old_note.fields.len().max(new_note.fields.len());
+        let fields = (0..field_count)
+            .filter_map(|i| {
+                let old_value = old_note
+                    .fields
+                    .get(i)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                let new_value = new_note
+                    .fields
+                    .get(i)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                (old_value != new_value).then(|| FieldChange {
+                    field: new.field_name(new_note.model_id, i),
+                    old: old_value.to_string(),
+                    new: new_value.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let change = NoteChange {
+            guid: guid.clone(),
+            model: (old_note.model_id != new_note.model_id)
+                .then_some((old_note.model_id, new_note.model_id)),
+            fields,
+            tags_added: new_note.tags.difference(&old_note.tags).cloned().collect(),
+            tags_removed: old_note.tags.difference(&new_note.tags).cloned().collect(),
+        };
+        if change.model.is_some()
+            || !change.fields.is_empty()
+            || !change.tags_added.is_empty()
+            || !change.tags_removed.is_empty()
+        {
+            diff.modified.push(change);
+        }
+    }
+    for (guid, old_note) in &old.notes {
+        if !new.notes.contains_key(guid) {
+            diff.removed.push(NoteSummary {
+                guid: guid.clone(),
+                sort_field: old.sort_field(old_note).to_string(),
+            });
+        }
+    }
+
+    diff
+}
+
+impl Display for ModelChangeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added => write!(f, "added"),
+            Self::Removed => write!(f, "removed"),
+            Self::Renamed { old } => write!(f, "renamed from \"{old}\""),
+            Self::Fields { old, new } => {
+                write!(f, "fields changed from {old:?} to {new:?}")
+            }
+            Self::Templates { old, new } => {
+                write!(f, "templates changed from {old:?} to {new:?}")
+            }
+            Self::TemplateEdited { template } => write!(f, "template \"{template}\" edited"),
+            Self::SortField { old, new } => {
+                write!(f, "sort field changed from {} to {}", old + 1, new + 1)
+            }
+            Self::Css => write!(f, "styling changed"),
+        }
+    }
+}
+
+/// Formats the diff as a Markdown changelog.
+impl Display for DeckDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes.");
+        }
+
+        if !self.models.is_empty() {
+            writeln!(f, "## Note types")?;
+            for model in &self.models {
+                write!(f, "- {} ({}): {}", model.name, model.id, model.kind)?;
+                if model.kind.requires_full_sync() {
+                    write!(f, " (requires a full sync)")?;
+                }
+                writeln!(f)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.added.is_empty() {
+            writeln!(f, "## Added notes ({})", self.added.len())?;
+            for note in &self.added {
+                writeln!(f, "- {}: {}", note.guid, note.sort_field)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.removed.is_empty() {
+            writeln!(f, "## Removed notes ({})", self.removed.len())?;
+            for note in &self.removed {
+                writeln!(f, "- {}: {}", note.guid, note.sort_field)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.modified.is_empty() {
+            writeln!(f, "## Modified notes ({})", self.modified.len())?;
+            for note in &self.modified {
+                writeln!(f, "- {}", note.guid)?;
+                if let Some((old, new)) = note.model {
+                    writeln!(f, "  - note type: {old} -> {new}")?;
+                }
+                for field in &note.fields {
+                    writeln!(f, "  - {}: {:?} -> {:?}", field.field, field.old, field.new)?;
+                }
+                if !note.tags_added.is_empty() {
+                    writeln!(f, "  - tags added: {}", note.tags_added.join(" "))?;
+                }
+                if !note.tags_removed.is_empty() {
+                    writeln!(f, "  - tags removed: {}", note.tags_removed.join(" "))?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Field, Model, ModelType, Note, Template};
+    use std::{io::Cursor, sync::Arc};
+
+    // a deck with one model and the notes as (guid, front, tags)
+    fn deck(fields: &[&str], qfmt: &str, notes: &[(&str, &str, &[&str])]) -> Deck {
+        let model = Arc::new(Model::new(
+            1,
+            "Basic".to_string(),
+            fields.iter().map(|f| Field::new(f.to_string())).collect(),
+            0,
+            String::new(),
+            ModelType::Standard,
+        ));
+        let template = Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            qfmt.to_string(),
+            String::new(),
+        ));
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        for (guid, front, tags) in notes {
+            let mut values = vec![front.to_string()];
+            values.resize(fields.len(), String::new());
+            let note = Note::new(
+                guid.to_string(),
+                model.clone(),
+                vec![template.clone()],
+                values,
+            )
+            .tags(tags.iter().map(|t| t.to_string()).collect());
+            deck.add_note(note);
+        }
+        deck
+    }
+
+    #[test]
+    fn finds_added_removed_and_modified_notes() {
+        let old = deck(
+            &["Front"],
+            "{{Front}}",
+            &[("a", "one", &["x"]), ("b", "two", &[]), ("c", "three", &[])],
+        );
+        let new = deck(
+            &["Front"],
+            "{{Front}}",
+            &[("a", "one!", &["y"]), ("b", "two", &[]), ("d", "four", &[])],
+        );
+        let diff = diff(
+            &Snapshot::from_deck(&old).unwrap(),
+            &Snapshot::from_deck(&new).unwrap(),
+        );
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].sort_field, "four");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].guid, "c");
+        assert_eq!(diff.modified.len(), 1);
+        let change = &diff.modified[0];
+        assert_eq!(change.guid, "a");
+        assert_eq!(change.fields.len(), 1);
+        assert_eq!(
+            (change.fields[0].old.as_str(), change.fields[0].new.as_str()),
+            ("one", "one!")
+        );
+        assert_eq!(change.tags_added, ["y"]);
+        assert_eq!(change.tags_removed, ["x"]);
+        assert!(diff.models.is_empty());
+        assert!(!diff.requires_full_sync());
+    }
+
+    #[test]
+    fn classifies_model_changes() {
+        let old = deck(&["Front"], "{{Front}}", &[("a", "one", &[])]);
+        let new = deck(&["Front", "Back"], "{{Front}}!", &[("a", "one", &[])]);
+        let diff = diff(
+            &Snapshot::from_deck(&old).unwrap(),
+            &Snapshot::from_deck(&new).unwrap(),
+        );
+        let kinds = diff.models.iter().map(|m| &m.kind).collect::<Vec<_>>();
+        assert!(matches!(
+            kinds[..],
+            [
+                ModelChangeKind::Fields { .. },
+                ModelChangeKind::TemplateEdited { .. }
+            ]
+        ));
+        assert!(diff.requires_full_sync());
+    }
+
+    #[test]
+    fn only_schema_changes_require_a_full_sync() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let cases = [
+            (ModelChangeKind::Added, false),
+            (ModelChangeKind::Removed, true),
+            (
+                ModelChangeKind::Renamed {
+                    old: "Old".to_string(),
+                },
+                false,
+            ),
+            (
+                ModelChangeKind::Fields {
+                    old: names(&["Front"]),
+                    new: names(&["Front", "Back"]),
+                },
+                true,
+            ),
+            (
+                ModelChangeKind::Templates {
+                    old: names(&["Card 1", "Card 2"]),
+                    new: names(&["Card 2", "Card 1"]),
+                },
+                true,
+            ),
+            (
+                ModelChangeKind::TemplateEdited {
+                    template: "Card 1".to_string(),
+                },
+                false,
+            ),
+            (ModelChangeKind::SortField { old: 0, new: 1 }, false),
+            (ModelChangeKind::Css, false),
+        ];
+        for (kind, expected) in cases {
+            assert_eq!(kind.requires_full_sync(), expected, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn formats_a_changelog() {
+        let old = deck(
+            &["Front"],
+            "{{Front}}",
+            &[("a", "one", &["x"]), ("c", "three", &[])],
+        );
+        let new = deck(
+            &["Front"],
+            "{{Front}}!",
+            &[("a", "one!", &["x"]), ("d", "four", &[])],
+        );
+        let diff = diff(
+            &Snapshot::from_deck(&old).unwrap(),
+            &Snapshot::from_deck(&new).unwrap(),
+        );
+        assert_eq!(
+            diff.to_string(),
+            "## Note types
+- Basic (1): template \"Card 1\" edited
+
+## Added notes (1)
+- d: four
+
+## Removed notes (1)
+- c: three
+
+## Modified notes (1)
+- a
+  - Front: \"one\" -> \"one!\"
+
+"
+        );
+        assert_eq!(DeckDiff::default().to_string(), "No changes.\n");
+    }
+
+    #[test]
+    fn a_deck_has_no_changes_from_its_written_package() {
+        let deck = deck(
+            &["Front", "Back"],
+            "{{Front}}",
+            &[
+                ("a", r#"<img src="a:b.png">"#, &["x"]),
+                ("b", "[sound:c?.mp3]", &[]),
+            ],
+        );
+        let mut apkg = Cursor::new(Vec::new());
+        deck.write(&mut apkg).unwrap();
+        apkg.set_position(0);
+
+        let written = Snapshot::read_apkg(apkg).unwrap();
+        let diff = diff(&Snapshot::from_deck(&deck).unwrap(), &written);
+        assert!(diff.is_empty(), "{diff}");
+    }
+}
@@ -0,0 +1,283 @@
+// filtered decks, which gather cards from other decks with a search when Anki rebuilds them
+
+use crate::{DeckJson, Error, id};
+use serde_json::Value;
+
+// Anki's limit on the number of cards a filter can pull in
+const MAX_LIMIT: u32 = 99999;
+
+/// The order in which a filtered deck's search picks cards, see [`FilteredDeck`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SearchOrder {
+    OldestSeenFirst,
+    #[default]
+    Random,
+    IncreasingIntervals,
+    DecreasingIntervals,
+    MostLapses,
+    OrderAdded,
+    OrderDue,
+    LatestAddedFirst,
+    /// Called relative overdueness in Anki versions before 23.10.
+    RetrievabilityAscending,
+}
+
+impl SearchOrder {
+    // the number Anki stores for the order
+    fn to_anki(self) -> i64 {
+        match self {
+            Self::OldestSeenFirst => 0,
+            Self::Random => 1,
+            Self::IncreasingIntervals => 2,
+            Self::DecreasingIntervals => 3,
+            Self::MostLapses => 4,
+            Self::OrderAdded => 5,
+            Self::OrderDue => 6,
+            Self::LatestAddedFirst => 7,
+            Self::RetrievabilityAscending => 8,
+        }
+    }
+}
+
+// a search that pulls cards into the deck
+#[derive(Debug, Clone)]
+struct Filter {
+    search: String,
+    limit: u32,
+    order: SearchOrder,
+}
+
+impl Filter {
+    fn to_value(&self) -> Value {
+        serde_json::json!([self.search, self.limit, self.order.to_anki()])
+    }
+}
+
+/// Anki filtered deck, which temporarily moves the cards matching a search out of their decks for studying them.
+/// For example, a deck with the search `deck:Course prop:lapses>3` gathers the hardest cards of the course.
+///
+/// The deck is written empty, and Anki fills it with the matching cards when the user rebuilds it.
+/// Filtered decks can't contain other decks.
+#[derive(Debug, Clone)]
+pub struct FilteredDeck {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    description: String,
+    filter: Filter,
+    second_filter: Option<Filter>,
+    reschedule: bool,
+    preview_secs: [u32; 3],
+}
+
+impl FilteredDeck {
+    /// Creates a new filtered deck that picks up to 100 random cards matching the search, using Anki's search syntax.
+    /// Note that the deck id 1 is special and corresponds to the default deck, and that the id should differ from the ids of the other decks.
+    pub fn new(id: i64, name: String, search: String) -> Self {
+        Self {
+            id,
+            name,
+            description: String::new(),
+            filter: Filter {
+                search,
+                limit: 100,
+                order: SearchOrder::Random,
+            },
+            second_filter: None,
+            reschedule: true,
+            preview_secs: [60, 600, 0],
+        }
+    }
+
+    /// Create a new filtered deck with an id derived from the namespace and name, see [`id::deck_id`].
+    pub fn from_name(namespace: &str, name: String, search: String) -> Self {
+        Self::new(id::deck_id(namespace, &name), name, search)
+    }
+
+    /// Set the deck description.
+    pub fn description(mut self, description: String) -> Self {
+        self.description = description;
+        self
+    }
+
+    /// Set the maximum number of cards the search pulls into the deck, at most 99999.
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.filter.limit = limit;
+        self
+    }
+
+    /// Set the order in which the search picks cards.
+    pub fn order(mut self, order: SearchOrder) -> Self {
+        self.filter.order = order;
+        self
+    }
+
+    /// Set a second search that pulls in more cards after the first one, with its own limit and order.
+    pub fn second_filter(mut self, search: String, limit: u32, order: SearchOrder) -> Self {
+        self.second_filter = Some(Filter {
+            search,
+            limit,
+            order,
+        });
+        self
+    }
+
+    /// Set whether answering the cards in the deck affects their scheduling. Enabled by default.
+    /// When disabled, the cards are only previewed and return to their decks unchanged.
+    pub fn reschedule(mut self, reschedule: bool) -> Self {
+        self.reschedule = reschedule;
+        self
+    }
+
+    /// Set how many seconds it takes for a previewed card to be shown again after answering it with Again, Hard or Good.
+    /// A delay of 0 for Good returns the card to its deck. Only used when the deck doesn't reschedule cards.
+    pub fn preview_delays(mut self, again_secs: u32, hard_secs: u32, good_secs: u32) -> Self {
+        self.preview_secs = [again_secs, hard_secs, good_secs];
+        self
+    }
+
+    fn filters(&self) -> impl Iterator<Item = &Filter> {
+        std::iter::once(&self.filter).chain(&self.second_filter)
+    }
+
+    // checks the deck's settings against the limits Anki has for them
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let reason = if self.id == 1 {
+            "the id 1 belongs to the default deck"
+        } else if self.filter.search.trim().is_empty() {
+            "the search should not be empty"
+        } else if self.filters().any(|f| f.limit == 0 || f.limit > MAX_LIMIT) {
+            "the limit should be between 1 and 99999"
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidFilteredDeck {
+            name: self.name.clone(),
+            reason,
+        })
+    }
+
+    pub(crate) fn to_value(&self, timestamp_millis: i64) -> Value {
+        let [again_secs, hard_secs, good_secs] = self.preview_secs;
+        let mut value = DeckJson {
+            id: self.id,
+            name: &self.name,
+            description: &self.description,
+            markdown_description: false,
+            collapsed: false,
+            browser_collapsed: false,
+            filtered: true,
+        }
+        .to_map(timestamp_millis);
+        value.extend([
+            // whether answers affect scheduling
+            ("resched".to_string(), Value::from(self.reschedule)),
+            // the searches, [search, limit, order]
+            (
+                "terms".to_string(),
+                self.filters().map(Filter::to_value).collect(),
+            ),
+            // legacy, always set by Anki
+            ("separate".to_string(), Value::from(true)),
+            // legacy custom learning steps, unused
+            ("delays".to_string(), Value::Null),
+            // legacy preview delay in minutes for the old scheduler
+            ("previewDelay".to_string(), Value::from(again_secs / 60)),
+            // preview delays for the answer buttons
+            ("previewAgainSecs".to_string(), Value::from(again_secs)),
+            ("previewHardSecs".to_string(), Value::from(hard_secs)),
+            ("previewGoodSecs".to_string(), Value::from(good_secs)),
+        ]);
+        Value::Object(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Collection, Deck};
+    use std::io::Cursor;
+
+    #[test]
+    fn validates_the_settings() {
+        let deck =
+            |id: i64, search: &str| FilteredDeck::new(id, "Review".to_string(), search.to_string());
+        assert!(deck(2, "deck:Course").limit(MAX_LIMIT).validate().is_ok());
+        let invalid = [
+            deck(1, "deck:Course"),
+            deck(2, " "),
+            deck(2, "deck:Course").limit(0),
+            deck(2, "deck:Course").limit(MAX_LIMIT + 1),
+            deck(2, "deck:Course").second_filter("is:due".to_string(), 0, SearchOrder::Random),
+        ];
+        for deck in invalid {
+            assert!(
+                matches!(deck.validate(), Err(Error::InvalidFilteredDeck { .. })),
+                "{deck:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_anki_json() {
+        let value = FilteredDeck::new(2, "Review".to_string(), "prop:lapses>3".to_string())
+            .limit(50)
+            .order(SearchOrder::MostLapses)
+            .second_filter("is:due".to_string(), 20, SearchOrder::OrderDue)
+            .reschedule(false)
+            .preview_delays(120, 600, 0)
+            .to_value(1000);
+        assert_eq!(value["dyn"], 1);
+        assert_eq!(
+            value["terms"],
+            serde_json::json!([["prop:lapses>3", 50, 4], ["is:due", 20, 6]])
+        );
+        assert_eq!(value["resched"], false);
+        assert_eq!(value["delays"], Value::Null);
+        assert_eq!(value["previewDelay"], 2);
+        assert_eq!(
+            [
+                &value["previewAgainSecs"],
+                &value["previewHardSecs"],
+                &value["previewGoodSecs"]
+            ],
+            [120, 600, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_nested_decks_and_clashing_ids() {
+        let write = |decks: Vec<Deck>, filtered_decks: Vec<FilteredDeck>| {
+            let mut collection = Collection::new();
+            for deck in decks {
+                collection.add_deck(deck);
+            }
+            for filtered_deck in filtered_decks {
+                collection.add_filtered_deck(filtered_deck);
+            }
+            collection.write_apkg(Cursor::new(Vec::new()))
+        };
+        let filtered = || FilteredDeck::new(2, "Review".to_string(), "is:due".to_string());
+
+        assert!(
+            write(
+                vec![Deck::new(3, "Course".to_string(), String::new())],
+                vec![filtered()]
+            )
+            .is_ok()
+        );
+        assert!(matches!(
+            write(
+                vec![Deck::new(3, "Review::Course".to_string(), String::new())],
+                vec![filtered()]
+            ),
+            Err(Error::InvalidFilteredDeck { .. })
+        ));
+        assert!(matches!(
+            write(
+                vec![Deck::new(2, "Course".to_string(), String::new())],
+                vec![filtered()]
+            ),
+            Err(Error::DuplicateDeckId { id: 2, .. })
+        ));
+    }
+}
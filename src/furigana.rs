@@ -0,0 +1,209 @@
+//! Writing Japanese readings in the bracket notation used by Anki's `furigana:`, `kana:` and `kanji:` template filters.
+//!
+//! A reading is written in brackets after the text it belongs to, like `漢字[かんじ]`.
+//! The text is everything after the previous space or HTML tag, so a space is needed to separate it from unannotated text before it,
+//! like `日本語[にほんご]を 勉強[べんきょう]する`. Anki hides these spaces when displaying the field through a filter.
+
+use crate::Error;
+
+/// Converts text split into segments with optional readings into bracket notation, adding spaces where they are needed.
+/// For example, `[("日本語", Some("にほんご")), ("を", None), ("勉強", Some("べんきょう")), ("する", None)]`
+/// is converted into `日本語[にほんご]を 勉強[べんきょう]する`.
+pub fn from_readings<'a, I>(segments: I) -> Result<String, Error>
+where
+    I: IntoIterator<Item = (&'a str, Option<&'a str>)>,
+{
+    let mut text = String::new();
+    for (base, reading) in segments {
+        match reading {
+            Some(reading) => {
+                if base.is_empty() || base.contains([' ', '>', '[', ']']) {
+                    return Err(invalid(
+                        base,
+                        "text with a reading should not be empty or contain spaces, '>' or brackets",
+                    ));
+                }
+                if reading.is_empty() || reading.contains(['[', ']', '\n']) {
+                    return Err(invalid(
+                        reading,
+                        "readings should not be empty or contain brackets or newlines",
+                    ));
+                }
+                if !text.is_empty() && !text.ends_with(' ') {
+                    text.push(' ');
+                }
+                text.push_str(base);
+                text.push('[');
+                text.push_str(reading);
+                text.push(']');
+            }
+            None => {
+                if base.contains(['[', ']']) {
+                    return Err(invalid(
+                        base,
+                        "text without a reading should not contain brackets",
+                    ));
+                }
+                text.push_str(base);
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// Checks that the brackets in the field value are written correctly,
+/// so that each reading belongs to some text and no brackets are left unmatched.
+pub fn validate(value: &str) -> Result<(), Error> {
+    parse(value).map(|_| ())
+}
+
+/// Converts the readings in the field value into ruby HTML like Anki's `furigana:` filter, for previewing the field.
+pub fn to_ruby(value: &str) -> Result<String, Error> {
+    render(value, |base, reading| {
+        format!("<ruby><rb>{base}</rb><rt>{reading}</rt></ruby>")
+    })
+}
+
+/// Replaces the text that has a reading with the reading, like Anki's `kana:` filter.
+pub fn kana(value: &str) -> Result<String, Error> {
+    render(value, |_base, reading| reading.to_string())
+}
+
+/// Removes the readings from the field value, like Anki's `kanji:` filter.
+pub fn kanji(value: &str) -> Result<String, Error> {
+    render(value, |base, _reading| base.to_string())
+}
+
+// a piece of text with a reading in a field value
+struct Annotation<'a> {
+    // the byte range of the annotation in the value, including the space before the text if any
+    start: usize,
+    end: usize,
+    base: &'a str,
+    reading: &'a str,
+}
+
+// replaces each annotation in the value with the output of the function
+fn render(value: &str, f: impl Fn(&str, &str) -> String) -> Result<String, Error> {
+    let mut rendered = String::with_capacity(value.len());
+    let mut last = 0;
+    for annotation in parse(value)? {
+        rendered.push_str(&value[last..annotation.start]);
+        rendered.push_str(&f(annotation.base, annotation.reading));
+        last = annotation.end;
+    }
+    rendered.push_str(&value[last..]);
+    Ok(rendered.replace("&nbsp;", " "))
+}
+
+// finds the annotations in the value the way Anki does
+fn parse(value: &str) -> Result<Vec<Annotation<'_>>, Error> {
+    let mut annotations = Vec::new();
+    // the start of the text that can belong to the next reading
+    let mut text_start = 0;
+    while let Some(found) = value[text_start..].find(['[', ']']) {
+        let open = text_start + found;
+        if value[open..].starts_with(']') {
+            return Err(invalid(value, "unmatched ']'"));
+        }
+        let Some(close) = value[open..].find(']').map(|close| open + close) else {
+            return Err(invalid(value, "unmatched '['"));
+        };
+        let reading = &value[open + 1..close];
+        if reading.contains('[') {
+            return Err(invalid(value, "nested '['"));
+        }
+        if reading.is_empty() || reading.contains('\n') {
+            return Err(invalid(value, "empty or multiline reading"));
+        }
+
+        // sound tags are written in brackets as well, but have no reading
+        let is_sound = reading.starts_with("sound:");
+        let before = &value[text_start..open];
+        let base_start = text_start + before.rfind([' ', '>']).map(|i| i + 1).unwrap_or_default();
+        if base_start == open && !is_sound {
+            return Err(invalid(
+                value,
+                "a reading should directly follow its text, which should not be wrapped in HTML tags",
+            ));
+        }
+        if !is_sound {
+            // the space separating the text from the text before it is part of the annotation
+            let start = if value[..base_start].ends_with(' ') {
+                base_start - 1
+            } else {
+                base_start
+            };
+            annotations.push(Annotation {
+                start,
+                end: close + 1,
+                base: &value[base_start..open],
+                reading,
+            });
+        }
+        text_start = close + 1;
+    }
+    Ok(annotations)
+}
+
+fn invalid(text: &str, reason: &'static str) -> Error {
+    Error::InvalidFurigana {
+        text: text.to_string(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_spaces_before_text_with_readings() {
+        let text = from_readings([
+            ("日本語", Some("にほんご")),
+            ("を", None),
+            ("勉強", Some("べんきょう")),
+            ("する", None),
+        ])
+        .unwrap();
+        assert_eq!(text, "日本語[にほんご]を 勉強[べんきょう]する");
+        assert_eq!(
+            from_readings([("a ", None), ("漢字", Some("かんじ"))]).unwrap(),
+            "a 漢字[かんじ]"
+        );
+        assert!(from_readings([("漢 字", Some("かんじ"))]).is_err());
+        assert!(from_readings([("漢字", Some(""))]).is_err());
+        assert!(from_readings([("[", None)]).is_err());
+    }
+
+    #[test]
+    fn renders_like_the_template_filters() {
+        let value = "日本語[にほんご]を 勉強[べんきょう]する[sound:a.mp3]";
+        assert_eq!(
+            to_ruby(value).unwrap(),
+            "<ruby><rb>日本語</rb><rt>にほんご</rt></ruby>を<ruby><rb>勉強</rb><rt>べんきょう</rt></ruby>する[sound:a.mp3]"
+        );
+        assert_eq!(
+            kana(value).unwrap(),
+            "にほんごをべんきょうする[sound:a.mp3]"
+        );
+        assert_eq!(kanji(value).unwrap(), "日本語を勉強する[sound:a.mp3]");
+        assert_eq!(kanji("<b>漢字[かんじ]</b>").unwrap(), "<b>漢字</b>");
+    }
+
+    #[test]
+    fn rejects_malformed_brackets() {
+        assert!(validate("漢字[かんじ] 仮名[かな]").is_ok());
+        for value in [
+            "漢字]",
+            "漢字[かんじ",
+            "漢字[か[ん]じ]",
+            "漢字[]",
+            "漢字[かん\nじ]",
+            " [かんじ]",
+            "<b>漢字</b>[かんじ]",
+        ] {
+            assert!(validate(value).is_err(), "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Generating note guids in the format Anki uses.
+//!
+//! Anki's guids are 64-bit numbers written in base 91.
+//! A note's guid is how Anki recognises it when a deck is imported again, so it should be unique and never change.
+
+use crate::{Error, error};
+use sha2::{Digest, Sha256};
+
+// the characters Anki uses for its base 91 guids
+const BASE91_TABLE: &[u8; 91] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// Generates a random guid, like Anki does for new notes.
+/// The guid needs to be stored with the note's source data so that the note keeps it between builds.
+pub fn random() -> Result<String, Error> {
+    let value = getrandom::u64()
+        .map_err(Box::from)
+        .map_err(error!(Error::Generic, "Failed to generate random guid"))?;
+    Ok(base91(value))
+}
+
+/// Derives a guid from a key, such as the note's sort field or a tuple of fields that identifies it.
+/// The same key always results in the same guid, so the note keeps its guid between builds as long as the key does not change.
+///
+/// The guids are compatible with genanki's `guid_for`, so decks can be migrated from it without duplicating notes.
+pub fn from_key(key: &[&str]) -> String {
+    let hash = Sha256::digest(key.join("__").as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    base91(u64::from_be_bytes(bytes))
+}
+
+/// Checks whether the guid only contains characters Anki uses in its guids.
+/// Other printable characters are accepted by Anki, but whitespace and quotes in particular break searches and other tools.
+pub fn is_valid(guid: &str) -> bool {
+    !guid.is_empty() && guid.bytes().all(|b| BASE91_TABLE.contains(&b))
+}
+
+/// Like [`is_valid`], but returns an error for guids with other characters, for checking guids while loading notes.
+/// Notes are written with whatever guid they have, since Anki accepts them and changing a guid duplicates the note for users.
+pub fn validate(guid: &str) -> Result<(), Error> {
+    if is_valid(guid) {
+        Ok(())
+    } else {
+        Err(Error::InvalidGuid {
+            guid: guid.to_string(),
+        })
+    }
+}
+
+// the value in base 91, 0 being "a" instead of an empty guid
+fn base91(mut value: u64) -> String {
+    let mut reversed = Vec::new();
+    loop {
+        reversed.push(BASE91_TABLE[(value % 91) as usize]);
+        value /= 91;
+        if value == 0 {
+            break;
+        }
+    }
+    reversed.iter().rev().map(|b| char::from(*b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_give_the_same_guids_as_genanki() {
+        // the values genanki's guid_for returns for the same keys
+        assert_eq!(from_key(&["hello"]), "hZ%+.BW-%^");
+        assert_eq!(from_key(&["Capital of France", "Paris"]), "hzBi7&,JaT");
+        assert_eq!(from_key(&["日本語"]), "ukuE~8Q2sV");
+        assert_eq!(from_key(&[""]), "ME_YHw2?15");
+    }
+
+    #[test]
+    fn random_guids_use_anki_characters() {
+        for _ in 0..100 {
+            let guid = random().unwrap();
+            // 91^10 > 2^64, so a 64-bit value has at most 10 digits
+            assert!((1..=10).contains(&guid.len()), "{guid}");
+            assert!(is_valid(&guid), "{guid}");
+        }
+        assert_ne!(random().unwrap(), random().unwrap());
+    }
+
+    #[test]
+    fn writes_base91() {
+        assert_eq!(base91(0), "a");
+        assert_eq!(base91(90), "~");
+        assert_eq!(base91(91), "ba");
+        assert_eq!(base91(u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn checks_guids() {
+        assert!(is_valid("hZ%+.BW-%^"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("vocab 1"));
+        assert!(matches!(
+            validate("vocab\"1"),
+            Err(Error::InvalidGuid { .. })
+        ));
+    }
+}
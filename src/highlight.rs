@@ -0,0 +1,220 @@
+//! Syntax highlighting code fields when the deck is built, so no JavaScript has to run when the cards are reviewed.
+
+use crate::{Error, error, html};
+use std::sync::OnceLock;
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+// the prefix of the classes in classed HTML, to avoid clashing with the model's own classes
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+// the class Anki adds to the card when night mode is enabled
+const NIGHT_MODE_CLASS: &str = ".nightMode";
+
+/// How highlighted code is styled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HighlightStyle {
+    /// The code is marked with classes and the CSS for the themes is added to the model's CSS.
+    /// Supports switching to the night theme in night mode.
+    #[default]
+    Classed,
+    /// The colours of the theme are written into the code's HTML.
+    /// Doesn't touch the model's CSS, but only the light theme is used.
+    Inline,
+}
+
+/// Settings for highlighting the code fields of a model, see [`crate::Field::code`].
+#[derive(Debug, Clone)]
+pub struct Highlighting {
+    style: HighlightStyle,
+    theme: String,
+    night_theme: Option<String>,
+}
+
+impl Default for Highlighting {
+    fn default() -> Self {
+        Self {
+            style: HighlightStyle::Classed,
+            theme: "InspiredGitHub".to_string(),
+            night_theme: Some("base16-ocean.dark".to_string()),
+        }
+    }
+}
+
+impl Highlighting {
+    /// The names of the themes that can be used.
+    pub fn themes() -> impl Iterator<Item = &'static str> {
+        theme_set().themes.keys().map(String::as_str)
+    }
+
+    /// Creates new highlighting settings with classed HTML, the "InspiredGitHub" theme and the "base16-ocean.dark" night theme.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how the highlighted code is styled.
+    pub fn style(mut self, style: HighlightStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Set the theme, see [`Highlighting::themes`].
+    pub fn theme(mut self, theme: String) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Set the theme used in night mode, or use the regular theme in night mode as well with `None`.
+    pub fn night_theme(mut self, night_theme: Option<String>) -> Self {
+        self.night_theme = night_theme;
+        self
+    }
+
+    // highlights the code into HTML, falling back to plain text if the language is not known
+    pub(crate) fn highlight(&self, language: &str, code: &str) -> String {
+        let syntaxes = syntax_set();
+        let syntax = find_syntax(syntaxes, language);
+        let highlighted = match self.style {
+            HighlightStyle::Classed => {
+                let mut generator =
+                    ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CLASS_STYLE);
+                LinesWithEndings::from(code)
+                    .try_for_each(|line| generator.parse_html_for_line_which_includes_newline(line))
+                    .map(|_| format!("<pre class=\"hl-code\">{}</pre>", generator.finalize()))
+                    .map_err(|err| err.to_string())
+            }
+            HighlightStyle::Inline => find_theme(&self.theme)
+                .map_err(|err| err.to_string())
+                .and_then(|theme| {
+                    syntect::html::highlighted_html_for_string(code, syntaxes, syntax, theme)
+                        .map_err(|err| err.to_string())
+                })
+                .map(|html| html.trim_end().to_string()),
+        };
+        highlighted.unwrap_or_else(|err| {
+            tracing::warn!(
+                "Failed to highlight code as {language}, writing it unhighlighted: {err}"
+            );
+            format!("<pre>{}</pre>", html::escape(code))
+        })
+    }
+
+    // the CSS needed by classed HTML, empty for inline styles
+    pub(crate) fn css(&self) -> String {
+        if self.style == HighlightStyle::Inline {
+            return String::new();
+        }
+        let mut css = theme_css(&self.theme, "");
+        if let Some(night_theme) = &self.night_theme {
+            css.push('\n');
+            css.push_str(&theme_css(night_theme, NIGHT_MODE_CLASS));
+        }
+        css
+    }
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+// finds the syntax by name or file extension, e.g. "Rust" or "rs"
+fn find_syntax<'a>(syntaxes: &'a SyntaxSet, language: &str) -> &'a SyntaxReference {
+    syntaxes.find_syntax_by_token(language).unwrap_or_else(|| {
+        tracing::warn!("Unknown language {language}, writing code fields as plain text");
+        syntaxes.find_syntax_plain_text()
+    })
+}
+
+// finds the theme by name, falling back to the default theme if it's not known
+fn find_theme(name: &str) -> Result<&'static Theme, Error> {
+    let themes = &theme_set().themes;
+    if let Some(theme) = themes.get(name) {
+        return Ok(theme);
+    }
+    let default = Highlighting::default().theme;
+    tracing::warn!("Unknown theme {name}, using {default} instead");
+    themes.get(&default).ok_or_else(|| Error::UnknownTheme {
+        name: name.to_string(),
+    })
+}
+
+// the CSS for the theme's classes, with each selector prefixed by the given selector if it's not empty
+fn theme_css(theme: &str, selector_prefix: &str) -> String {
+    let css = find_theme(theme).and_then(|theme| {
+        syntect::html::css_for_theme_with_class_style(theme, CLASS_STYLE)
+            .map_err(Box::from)
+            .map_err(error!(
+                Error::Generic,
+                "Failed to generate CSS for the theme"
+            ))
+    });
+    let css = match css {
+        Ok(css) => css,
+        Err(err) => {
+            tracing::warn!("Failed to generate CSS for theme {theme}: {err}");
+            return String::new();
+        }
+    };
+    if selector_prefix.is_empty() {
+        return css;
+    }
+
+    let mut prefixed = String::with_capacity(css.len());
+    for line in css.lines() {
+        // declarations and comments are on their own lines, so only rule headers end with an opening brace
+        match line.strip_suffix(" {") {
+            Some(selectors) => {
+                let selectors = selectors
+                    .split(", ")
+                    .map(|s| format!("{selector_prefix} {s}"))
+                    .collect::<Vec<_>>();
+                prefixed.push_str(&selectors.join(", "));
+                prefixed.push_str(" {");
+            }
+            None => prefixed.push_str(line),
+        }
+        prefixed.push('\n');
+    }
+    prefixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_theme_falls_back_to_default() {
+        let theme = find_theme("no such theme").unwrap();
+        assert!(std::ptr::eq(theme, find_theme("InspiredGitHub").unwrap()));
+
+        let highlighting = Highlighting::new()
+            .style(HighlightStyle::Inline)
+            .theme("no such theme".to_string());
+        assert!(
+            highlighting
+                .highlight("rs", "fn main() {}")
+                .contains("style=")
+        );
+    }
+
+    #[test]
+    fn unknown_language_is_escaped() {
+        let html = Highlighting::new().highlight("no such language", "a < b");
+        assert!(html.contains("a &lt; b"), "{html}");
+    }
+
+    #[test]
+    fn night_theme_css_is_prefixed() {
+        let css = Highlighting::new().css();
+        assert!(css.contains(".nightMode .hl-"), "{css}");
+    }
+}
@@ -0,0 +1,354 @@
+//! Escaping, stripping and sanitising HTML in field values.
+
+#[cfg(feature = "sanitize")]
+use std::collections::{BTreeSet, HashMap};
+
+// tags that are always removed together with their contents when sanitising
+#[cfg(feature = "sanitize")]
+const CLEAN_CONTENT_TAGS: [&str; 2] = ["script", "style"];
+
+/// Escapes the characters that have a special meaning in HTML, so that the text is displayed as is.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Removes the tags, comments, scripts and styles from the HTML and decodes its entities, leaving the text that is displayed.
+pub fn strip_html(html: &str) -> String {
+    decode_entities(&strip_tags(html, false))
+}
+
+// like strip_html, but keeps the file names of images like Anki does for the sort field
+pub(crate) fn strip_html_preserving_media_filenames(html: &str) -> String {
+    decode_entities(&strip_tags(html, true))
+}
+
+fn strip_tags(html: &str, keep_image_sources: bool) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment
+                .find("-->")
+                .map(|end| &comment[end + 3..])
+                .unwrap_or_default();
+            continue;
+        }
+        let is_tag = rest[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        if !is_tag {
+            text.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        let name = tag_name(tag);
+        if name.eq_ignore_ascii_case("script") || name.eq_ignore_ascii_case("style") {
+            // skip the contents as well
+            let close = format!("</{}", name.to_ascii_lowercase());
+            rest = rest
+                .to_ascii_lowercase()
+                .find(&close)
+                .and_then(|close_start| {
+                    let close_end = rest[close_start..].find('>')?;
+                    Some(&rest[close_start + close_end + 1..])
+                })
+                .unwrap_or_default();
+        } else if keep_image_sources && name.eq_ignore_ascii_case("img") {
+            if let Some(src) = attribute(tag, "src") {
+                text.push(' ');
+                text.push_str(src);
+                text.push(' ');
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+// the name of the tag, e.g. "img" for <img src="a.png">
+pub(crate) fn tag_name(tag: &str) -> &str {
+    let tag = tag.trim_start_matches('<');
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+// the value of the attribute in the tag, e.g. "a.png" for the src attribute in <img src="a.png">
+pub(crate) fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(found) = lowercase[search_from..].find(name) {
+        let start = search_from + found;
+        search_from = start + name.len();
+        let preceded_by_space = lowercase[..start]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_whitespace);
+        let value = tag[start + name.len()..].trim_start();
+        let Some(value) = value.strip_prefix('=').filter(|_| preceded_by_space) else {
+            continue;
+        };
+        let value = value.trim_start();
+        return match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value = &value[1..];
+                value.find(quote).map(|end| &value[..end])
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(value.len());
+                Some(value[..end].trim_end_matches('/'))
+            }
+        };
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        // Anki treats non-breaking spaces as regular spaces in the sort field
+        "nbsp" => ' ',
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some(c)
+}
+
+/// The tags and attributes that are kept when sanitising HTML.
+#[cfg(feature = "sanitize")]
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    tags: BTreeSet<String>,
+    attributes: BTreeSet<String>,
+}
+
+#[cfg(feature = "sanitize")]
+impl Default for Allowlist {
+    fn default() -> Self {
+        let tags = [
+            "a",
+            "b",
+            "blockquote",
+            "br",
+            "code",
+            "del",
+            "div",
+            "em",
+            "h1",
+            "h2",
+            "h3",
+            "h4",
+            "h5",
+            "h6",
+            "hr",
+            "i",
+            "img",
+            "li",
+            "ol",
+            "p",
+            "pre",
+            "rp",
+            "rt",
+            "ruby",
+            "s",
+            "span",
+            "strong",
+            "sub",
+            "sup",
+            "table",
+            "tbody",
+            "td",
+            "th",
+            "thead",
+            "tr",
+            "u",
+            "ul",
+        ];
+        let attributes = ["alt", "class", "href", "src", "title"];
+        Self {
+            tags: tags.into_iter().map(str::to_string).collect(),
+            attributes: attributes.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "sanitize")]
+impl Allowlist {
+    /// Creates the default allowlist, which keeps basic formatting, lists, tables, links, images and ruby text.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an allowlist that keeps nothing but text.
+    pub fn empty() -> Self {
+        Self {
+            tags: BTreeSet::new(),
+            attributes: BTreeSet::new(),
+        }
+    }
+
+    /// Allow the tag. `script` and `style` tags are always removed together with their contents.
+    pub fn tag(mut self, tag: String) -> Self {
+        self.tags.insert(tag);
+        self
+    }
+
+    /// Allow the attribute on all allowed tags.
+    pub fn attribute(mut self, attribute: String) -> Self {
+        self.attributes.insert(attribute);
+        self
+    }
+
+    // removes everything that is not allowed from the HTML
+    pub(crate) fn sanitize(&self, html: &str) -> String {
+        let tags = self
+            .tags
+            .iter()
+            .map(String::as_str)
+            .filter(|tag| {
+                !CLEAN_CONTENT_TAGS
+                    .iter()
+                    .any(|clean| clean.eq_ignore_ascii_case(tag))
+            })
+            .collect();
+        // ammonia's default attributes for specific tags and its rel attribute for links
+        // would keep or add more than the allowlist permits
+        ammonia::Builder::new()
+            .tags(tags)
+            .tag_attributes(HashMap::new())
+            .generic_attributes(self.attributes.iter().map(String::as_str).collect())
+            .link_rel(None)
+            .clean_content_tags(CLEAN_CONTENT_TAGS.into_iter().collect())
+            .clean(html)
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(
+            escape(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn strips_tags_comments_scripts_and_styles() {
+        assert_eq!(
+            strip_html("<b>a</b><!-- c --><script>x()</script>&lt;b&nbsp;<style>p {}</STYLE>c"),
+            "a<b c"
+        );
+        assert_eq!(strip_html("1 < 2"), "1 < 2");
+        assert_eq!(
+            strip_html_preserving_media_filenames("a<img src='b.png'>c"),
+            "a b.png c"
+        );
+    }
+
+    #[test]
+    fn finds_tag_names_and_attributes() {
+        assert_eq!(tag_name("<img src=a.png>"), "img");
+        assert_eq!(tag_name("<br/>"), "br");
+        assert_eq!(
+            attribute("<img data-src='x' src=\"a.png\">", "src"),
+            Some("a.png")
+        );
+        assert_eq!(attribute("<img src=a.png/>", "src"), Some("a.png"));
+        assert_eq!(attribute("<img alt=x>", "src"), None);
+    }
+
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn sanitizing_keeps_only_the_allowlist() {
+        let allowlist = Allowlist::empty()
+            .tag("a".to_string())
+            .tag("img".to_string());
+        assert_eq!(
+            allowlist.sanitize(
+                r#"<a href="https://example.com" title="t">x</a><img src="a.png" alt="a"><b>y</b>"#
+            ),
+            "<a>x</a><img>y"
+        );
+        let allowlist = allowlist.attribute("href".to_string());
+        assert_eq!(
+            allowlist.sanitize(r#"<a href="https://example.com">x</a>"#),
+            r#"<a href="https://example.com">x</a>"#
+        );
+    }
+
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn sanitizing_with_entries_ammonia_rejects_does_not_panic() {
+        let allowlist = Allowlist::new()
+            .attribute("rel".to_string())
+            .tag("script".to_string())
+            .tag("style".to_string());
+        assert_eq!(
+            allowlist.sanitize(
+                r#"<a href="x" rel="nofollow">a</a><script>b()</script><style>p {}</style>"#
+            ),
+            r#"<a href="x" rel="nofollow">a</a>"#
+        );
+    }
+}
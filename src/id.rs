@@ -0,0 +1,163 @@
+//! Deriving stable model, template and deck ids from names.
+//!
+//! Anki identifies models and decks by their ids, so the ids need to stay the same between builds and
+//! should not collide with the ids of other people's models and decks.
+//! Ids derived from a namespace, such as your organisation or project, and a name satisfy both.
+
+use crate::{Error, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{Read, Write},
+};
+
+// ids are derived in the range [2^42, 2^53), which is above the millisecond timestamps Anki uses as ids
+// and small enough to be represented exactly in JavaScript
+const MIN_ID: u64 = 1 << 42;
+const MAX_ID: u64 = 1 << 53;
+
+/// Derives a model id from a namespace and the model's name.
+pub fn model_id(namespace: &str, name: &str) -> i64 {
+    derive("model", namespace, name)
+}
+
+/// Derives a template id from a namespace and the template's name.
+/// Templates are only distinguished within a model, so the namespace should include the model's name.
+pub fn template_id(namespace: &str, name: &str) -> i64 {
+    derive("template", namespace, name)
+}
+
+/// Derives a deck id from a namespace and the deck's name.
+pub fn deck_id(namespace: &str, name: &str) -> i64 {
+    derive("deck", namespace, name)
+}
+
+fn derive(kind: &str, namespace: &str, name: &str) -> i64 {
+    let hash = Sha256::digest(format!("{kind}\x1f{namespace}\x1f{name}").as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    let id = MIN_ID + u64::from_be_bytes(bytes) % (MAX_ID - MIN_ID);
+    id as i64
+}
+
+/// A lockfile of the ids used for models, templates and decks.
+/// Once an id has been recorded for a name it is used even if the way ids are derived changes,
+/// and the ids can be reassigned by hand in the file if, for example, a model is renamed.
+/// Derived ids that collide with an id already in the registry are bumped until they are unique.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IdRegistry {
+    // "namespace::name" => id
+    models: BTreeMap<String, i64>,
+    templates: BTreeMap<String, i64>,
+    decks: BTreeMap<String, i64>,
+}
+
+impl IdRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a registry saved with [`IdRegistry::write`].
+    pub fn read<R: Read>(reader: R) -> Result<Self, Error> {
+        serde_json::from_reader(reader).map_err(error!(Error::Json, "Failed to read id registry"))
+    }
+
+    /// Write the registry as JSON.
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), Error> {
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(error!(Error::Json, "Failed to write id registry"))
+    }
+
+    /// Returns the recorded id for the model, or derives and records one with [`model_id`].
+    pub fn model_id(&mut self, namespace: &str, name: &str) -> i64 {
+        Self::get_or_derive(&mut self.models, "model", namespace, name)
+    }
+
+    /// Returns the recorded id for the template, or derives and records one with [`template_id`].
+    pub fn template_id(&mut self, namespace: &str, name: &str) -> i64 {
+        Self::get_or_derive(&mut self.templates, "template", namespace, name)
+    }
+
+    /// Returns the recorded id for the deck, or derives and records one with [`deck_id`].
+    pub fn deck_id(&mut self, namespace: &str, name: &str) -> i64 {
+        Self::get_or_derive(&mut self.decks, "deck", namespace, name)
+    }
+
+    fn get_or_derive(
+        ids: &mut BTreeMap<String, i64>,
+        kind: &str,
+        namespace: &str,
+        name: &str,
+    ) -> i64 {
+        let key = format!("{namespace}::{name}");
+        if let Some(id) = ids.get(&key) {
+            return *id;
+        }
+
+        let used = ids.values().copied().collect::<HashSet<_>>();
+        let mut id = derive(kind, namespace, name);
+        while used.contains(&id) {
+            id += 1;
+        }
+        ids.insert(key, id);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_ids_are_stable_and_in_range() {
+        let ids = [
+            model_id("org", "Basic"),
+            template_id("org::Basic", "Card 1"),
+            deck_id("org", "Course"),
+            deck_id("", ""),
+        ];
+        for id in ids {
+            assert!((MIN_ID as i64..MAX_ID as i64).contains(&id), "{id}");
+        }
+        assert_eq!(model_id("org", "Basic"), ids[0]);
+        // the kind and the separators are part of what is hashed
+        assert_ne!(model_id("org", "Course"), deck_id("org", "Course"));
+        assert_ne!(deck_id("a", "bc"), deck_id("ab", "c"));
+    }
+
+    #[test]
+    fn the_registry_bumps_colliding_ids() {
+        let mut registry = IdRegistry::new();
+        let derived = deck_id("org", "Course");
+        registry.decks.insert("org::Renamed".to_string(), derived);
+        registry.decks.insert("org::Other".to_string(), derived + 1);
+
+        assert_eq!(registry.deck_id("org", "Course"), derived + 2);
+        // recorded ids are returned as they are
+        assert_eq!(registry.deck_id("org", "Course"), derived + 2);
+        assert_eq!(registry.deck_id("org", "Renamed"), derived);
+        // each kind has its own ids
+        assert_eq!(
+            registry.model_id("org", "Course"),
+            model_id("org", "Course")
+        );
+    }
+
+    #[test]
+    fn the_registry_round_trips() {
+        let mut registry = IdRegistry::new();
+        let model = registry.model_id("org", "Basic");
+        let template = registry.template_id("org::Basic", "Card 1");
+        registry.decks.insert("org::Course".to_string(), 5);
+
+        let mut json = Vec::new();
+        registry.write(&mut json).unwrap();
+        let mut read = IdRegistry::read(json.as_slice()).unwrap();
+        assert_eq!(read.model_id("org", "Basic"), model);
+        assert_eq!(read.template_id("org::Basic", "Card 1"), template);
+        assert_eq!(read.deck_id("org", "Course"), 5);
+        assert!(IdRegistry::read(&b"{"[..]).is_err());
+    }
+}
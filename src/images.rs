@@ -0,0 +1,215 @@
+//! Resizing and re-encoding images before they are written, to keep packages small.
+
+use crate::{Error, Media, error};
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+};
+use std::io::Cursor;
+
+/// The format images are re-encoded into.
+/// Lossy WebP is not offered because the `image` crate can only encode WebP losslessly, JPEG is the lossy option.
+#[derive(Debug, Clone, Copy)]
+pub enum ImageEncoding {
+    /// JPEG with the given quality from 1 to 100. Transparency is lost, so this is best suited for photos.
+    Jpeg { quality: u8 },
+    /// Lossless WebP, which keeps transparency.
+    WebpLossless,
+}
+
+impl ImageEncoding {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg { .. } => "jpg",
+            Self::WebpLossless => "webp",
+        }
+    }
+}
+
+/// Settings for processing the JPEG, PNG, WebP and BMP images of a package, see [`crate::WriteOptions::process_images`].
+///
+/// The images are decoded, rotated according to their EXIF orientation, optionally downscaled and re-encoded,
+/// which leaves out their EXIF and other metadata.
+/// Images that change format are renamed to the new extension, with a number added if another file already has that name,
+/// and references to them are updated to match. Images that would not get smaller are kept as they are,
+/// unless they have EXIF, XMP or IPTC metadata, which may contain private details such as where a photo was taken.
+/// Files whose names start with an underscore, such as model assets, are left as they are, see [`crate::Model::asset`].
+#[derive(Debug, Clone)]
+pub struct ImageProcessing {
+    encoding: ImageEncoding,
+    max_dimension: Option<u32>,
+}
+
+impl ImageProcessing {
+    /// Creates new image processing settings that re-encode images without resizing them.
+    pub fn new(encoding: ImageEncoding) -> Self {
+        Self {
+            encoding,
+            max_dimension: None,
+        }
+    }
+
+    /// Set the maximum width and height of images. Larger images are downscaled to fit, keeping their aspect ratio.
+    pub fn max_dimension(mut self, max_dimension: u32) -> Self {
+        self.max_dimension = Some(max_dimension);
+        self
+    }
+
+    // re-encodes the media file if it's an image that can be processed
+    pub(crate) fn process(&self, media: &Media) -> Result<Option<Media>, Error> {
+        let reader = ImageReader::new(Cursor::new(&media.data))
+            .with_guessed_format()
+            .map_err(error!(Error::Io, "Failed to read image"))?;
+        // animated GIFs and other formats are left as they are
+        if !matches!(
+            reader.format(),
+            Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Bmp)
+        ) {
+            return Ok(None);
+        }
+
+        let mut decoder = reader
+            .into_decoder()
+            .map_err(error!(Error::Image, "Failed to decode image"))?;
+        let has_metadata = decoder
+            .exif_metadata()
+            .map_err(error!(Error::Image, "Failed to read image metadata"))?
+            .is_some()
+            || decoder
+                .xmp_metadata()
+                .map_err(error!(Error::Image, "Failed to read image metadata"))?
+                .is_some()
+            || decoder
+                .iptc_metadata()
+                .map_err(error!(Error::Image, "Failed to read image metadata"))?
+                .is_some();
+        let orientation = decoder
+            .orientation()
+            .map_err(error!(Error::Image, "Failed to read image orientation"))?;
+        let mut image = DynamicImage::from_decoder(decoder)
+            .map_err(error!(Error::Image, "Failed to decode image"))?;
+        image.apply_orientation(orientation);
+        if let Some(max) = self.max_dimension {
+            if image.width() > max || image.height() > max {
+                image = image.resize(max, max, image::imageops::FilterType::Lanczos3);
+            }
+        }
+
+        let mut data = Vec::new();
+        match self.encoding {
+            ImageEncoding::Jpeg { quality } => image
+                .to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut data, quality))
+                .map_err(error!(Error::Image, "Failed to encode image as JPEG"))?,
+            ImageEncoding::WebpLossless => image
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut data))
+                .map_err(error!(Error::Image, "Failed to encode image as WebP"))?,
+        }
+        if data.len() >= media.data.len() && !has_metadata {
+            tracing::debug!(
+                "Keeping image {:?} as it is, processing it would not make it smaller",
+                media.name
+            );
+            return Ok(None);
+        }
+        tracing::debug!(
+            "Processed image {:?} from {} to {} bytes",
+            media.name,
+            media.data.len(),
+            data.len()
+        );
+
+        let stem = media
+            .name
+            .rsplit_once('.')
+            .map(|(stem, _extension)| stem)
+            .unwrap_or(&media.name);
+        Ok(Some(Media {
+            name: format!("{stem}.{}", self.encoding.extension()),
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{ImageEncoder, RgbImage, codecs::png::PngEncoder};
+
+    // a PNG of noise, which compresses badly as PNG but well as a low quality JPEG
+    pub(crate) fn noise_png(size: u32) -> Vec<u8> {
+        let mut state = 1u32;
+        let image = RgbImage::from_fn(size, size, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [r, g, b, _] = state.to_be_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut data = Vec::new();
+        PngEncoder::new(&mut data)
+            .write_image(&image, size, size, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        data
+    }
+
+    fn media(name: &str, data: Vec<u8>) -> Media {
+        Media {
+            name: name.to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn reencodes_and_renames_images() {
+        let processing =
+            ImageProcessing::new(ImageEncoding::Jpeg { quality: 50 }).max_dimension(32);
+        let original = media("a.b.png", noise_png(64));
+        let processed = processing.process(&original).unwrap().unwrap();
+        assert_eq!(processed.name, "a.b.jpg");
+        assert!(processed.data.len() < original.data.len());
+        let image = image::load_from_memory(&processed.data).unwrap();
+        assert_eq!((image.width(), image.height()), (32, 32));
+    }
+
+    #[test]
+    fn keeps_images_that_would_not_get_smaller() {
+        let processing = ImageProcessing::new(ImageEncoding::Jpeg { quality: 100 });
+        assert!(
+            processing
+                .process(&media("a.png", noise_png(1)))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn always_strips_metadata() {
+        let mut data = Vec::new();
+        let mut encoder = PngEncoder::new(&mut data);
+        // a big-endian TIFF header without any entries
+        encoder
+            .set_exif_metadata(b"MM\x00\x2a\x00\x00\x00\x08\x00\x00".to_vec())
+            .unwrap();
+        encoder
+            .write_image(&[0, 0, 0], 1, 1, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let original = media("a.png", data);
+
+        let processing = ImageProcessing::new(ImageEncoding::Jpeg { quality: 100 });
+        let processed = processing.process(&original).unwrap().unwrap();
+        assert!(processed.data.len() > original.data.len());
+        let mut decoder = ImageReader::new(Cursor::new(&processed.data))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert!(decoder.exif_metadata().unwrap().is_none());
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let processing = ImageProcessing::new(ImageEncoding::WebpLossless);
+        let gif = media("a.gif", b"GIF89a\x01\x00\x01\x00\x00\x00\x00;".to_vec());
+        assert!(processing.process(&gif).unwrap().is_none());
+    }
+}
@@ -1,18 +1,52 @@
+#[cfg(feature = "ankiconnect")]
+pub mod ankiconnect;
+mod collection;
+pub mod diff;
+mod filtered_deck;
+pub mod furigana;
+pub mod guid;
+#[cfg(feature = "highlight")]
+pub mod highlight;
+pub mod html;
+pub mod id;
+#[cfg(feature = "images")]
+pub mod images;
+#[cfg(feature = "markdown")]
+mod markdown;
+mod media;
+mod media_type;
+pub mod routing;
 mod schema;
+pub mod sound;
+pub mod tags;
+mod update;
+
+pub use collection::{BrowserSort, Collection, CollectionConfig, NewSpread};
+pub use filtered_deck::{FilteredDeck, SearchOrder};
+pub use id::IdRegistry;
+pub use media::MediaReport;
+pub use update::BuildState;
+use update::Ids;
 
 use diesel::{ConnectionError, SqliteConnection, prelude::*};
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
+use media::MediaFiles;
 use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    borrow::Cow,
+    collections::{HashMap, HashSet, hash_map::Entry},
     io::{Seek, Write},
+    path::PathBuf,
     sync::Arc,
-    time::UNIX_EPOCH,
+    time::{Duration, UNIX_EPOCH},
 };
 use thiserror::Error;
-use zip::{ZipWriter, write::SimpleFileOptions};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
 
 const MIGRATIONS: EmbeddedMigrations = diesel_migrations::embed_migrations!();
+// the maximum number of bound parameters in a single statement for the bundled sqlite
+const SQLITE_MAX_VARIABLES: usize = 32766;
 
 /// reanki error type.
 #[derive(Error, Debug)]
@@ -42,8 +76,95 @@ pub enum Error {
         message: &'static str,
         source: Box<dyn std::error::Error + Send + Sync>,
     },
-    #[error("Diesel error")]
+    #[error("JSON error: {message}. Caused by: {source}")]
+    Json {
+        message: &'static str,
+        source: serde_json::Error,
+    },
+    #[error(
+        "Invalid note guid {guid:?}, guids should only contain the characters Anki uses in its guids"
+    )]
+    InvalidGuid { guid: String },
+    #[error("Invalid furigana in {text:?}: {reason}")]
+    InvalidFurigana { text: String, reason: &'static str },
+    #[error("Invalid sound or TTS value {value:?}: {reason}")]
+    InvalidSound { value: String, reason: &'static str },
+    #[error("Media file {name:?} was not found in the deck")]
+    MissingMedia { name: String },
+    #[error("Invalid media file {name:?}: {reason}")]
+    InvalidMedia { name: String, reason: String },
+    #[error("Invalid media file name {name:?}, Anki would rename it to {normalized:?}")]
+    InvalidMediaName { name: String, normalized: String },
+    #[error("Invalid tag {tag:?}: {reason}")]
+    InvalidTag { tag: String, reason: &'static str },
+    #[error("Invalid filtered deck {name:?}: {reason}")]
+    InvalidFilteredDeck { name: String, reason: &'static str },
+    #[error("Error recording the deletion of the note with the guid {guid:?}. Caused by: {source}")]
+    DeletedNote { guid: String, source: Box<Error> },
+    #[error("Multiple notes have the guid {guid:?}")]
+    DuplicateGuid { guid: String },
+    #[error("The decks {first:?} and {second:?} have the same id {id}")]
+    DuplicateDeckId {
+        id: i64,
+        first: String,
+        second: String,
+    },
+    #[error("Failed to get the current time. Caused by: {source}")]
+    Time { source: std::time::SystemTimeError },
+    #[error(
+        "The id of model {name:?} has changed from {previous} to {id} since the previous build, users would get a new copy of the model"
+    )]
+    ChangedModelId {
+        name: String,
+        previous: i64,
+        id: i64,
+    },
+    #[error("Model {model_id} has not been added to the deck")]
+    MissingModel { model_id: i64 },
+    #[error(
+        "Error in note {index} with the guid {guid:?} and model {model_id} in deck {deck:?}. Caused by: {source}"
+    )]
+    Note {
+        deck: String,
+        /// The index of the note in the deck, in the order the notes were added.
+        index: usize,
+        guid: String,
+        model_id: i64,
+        source: Box<Error>,
+    },
+    #[error("Database error. Caused by: {0}")]
     DieselFrom(#[from] diesel::result::Error),
+    #[cfg(feature = "images")]
+    #[error("Image error: {message}. Caused by: {source}")]
+    Image {
+        message: &'static str,
+        source: image::ImageError,
+    },
+    #[cfg(feature = "highlight")]
+    #[error("Unknown highlighting theme {name:?}")]
+    UnknownTheme { name: String },
+    #[cfg(feature = "ankiconnect")]
+    #[error("HTTP error: {message}. Caused by: {source}")]
+    Http {
+        message: &'static str,
+        source: ureq::Error,
+    },
+    #[cfg(feature = "ankiconnect")]
+    #[error("AnkiConnect error: {message}")]
+    AnkiConnect { message: String },
+}
+
+impl Error {
+    // adds the note's details to the error
+    fn in_note(self, deck: &Deck, index: usize, note: &Note) -> Self {
+        Self::Note {
+            deck: deck.name.clone(),
+            index,
+            guid: note.guid.clone(),
+            model_id: note.model.id,
+            source: Box::new(self),
+        }
+    }
 }
 
 macro_rules! error {
@@ -54,47 +175,112 @@ macro_rules! error {
         }
     };
 }
+use error;
 
-// deck config json object
+/// Anki deck options group, shared by the decks that use it.
 #[derive(Debug)]
-struct Dconf {
-    deck_id: i64,
+pub struct DeckConfig {
+    id: i64,
+    name: String,
+    new_per_day: Option<i64>,
+    reviews_per_day: Option<i64>,
+    autoplay: bool,
+    show_timer: bool,
+    max_taken: i64,
 }
 
-impl Dconf {
-    fn new(deck_id: i64) -> Self {
-        Self { deck_id }
+impl DeckConfig {
+    /// Creates a new DeckConfig with Anki's default limits. Note that the id 1 is special and corresponds to the default options group.
+    pub fn new(id: i64, name: String) -> Self {
+        Self {
+            id,
+            name,
+            new_per_day: None,
+            reviews_per_day: None,
+            autoplay: false,
+            show_timer: false,
+            max_taken: 0,
+        }
+    }
+
+    /// Set the maximum number of new cards introduced per day.
+    pub fn new_per_day(mut self, new_per_day: i64) -> Self {
+        self.new_per_day = Some(new_per_day);
+        self
+    }
+
+    /// Set the maximum number of reviews per day.
+    pub fn reviews_per_day(mut self, reviews_per_day: i64) -> Self {
+        self.reviews_per_day = Some(reviews_per_day);
+        self
+    }
+
+    /// Set whether audio is played automatically when a card is shown.
+    pub fn autoplay(mut self, autoplay: bool) -> Self {
+        self.autoplay = autoplay;
+        self
+    }
+
+    /// Set whether the answer timer is shown.
+    pub fn show_timer(mut self, show_timer: bool) -> Self {
+        self.show_timer = show_timer;
+        self
+    }
+
+    /// Set the number of seconds after which the answer timer stops counting.
+    pub fn max_taken(mut self, max_taken: i64) -> Self {
+        self.max_taken = max_taken;
+        self
     }
 
     fn to_anki_json(&self, dconf_timestamp: i64) -> Value {
+        let mut new = Map::new();
+        if let Some(new_per_day) = self.new_per_day {
+            new.insert("perDay".to_string(), new_per_day.into());
+        }
+        let mut rev = Map::new();
+        if let Some(reviews_per_day) = self.reviews_per_day {
+            rev.insert("perDay".to_string(), reviews_per_day.into());
+        }
+
         serde_json::json!({
-            dconf_timestamp.to_string(): {
-            //self.deck_id.to_string(): {
-                // deck id
-                "id": self.deck_id,
-                // modified timestamp
-                "mod": dconf_timestamp,
-                // deck config name
-                "name": "reanki-dconf",
-                // "update sequence number"
-                "usn": 0,
-                // timer max
-                "maxTaken": 0,
-                // autoplay audio
-                "autoplay": false,
-                // timer, hide = 0, show = 1
-                "timer": 0,
-                // new card conf
-                "new": {},
-                // review card conf
-                "rev": {},
-                // lapsed card conf
-                "lapse": {},
-            }
+            // deck config id
+            "id": self.id,
+            // modified timestamp
+            "mod": dconf_timestamp,
+            // deck config name
+            "name": self.name,
+            // "update sequence number"
+            "usn": 0,
+            // timer max
+            "maxTaken": self.max_taken,
+            // autoplay audio
+            "autoplay": self.autoplay,
+            // timer, hide = 0, show = 1
+            "timer": i64::from(self.show_timer),
+            // new card conf, missing keys use Anki's defaults
+            "new": new,
+            // review card conf, missing keys use Anki's defaults
+            "rev": rev,
+            // lapsed card conf
+            "lapse": {},
         })
     }
 }
 
+/// How a field's values are turned into the HTML that Anki displays.
+#[derive(Debug, Clone, Default)]
+pub enum FieldContent {
+    /// The values are HTML and are written as is.
+    #[default]
+    Html,
+    /// The values are plain text, characters like `<` are escaped so that the text is displayed as is.
+    PlainText,
+    /// The values are HTML, but only the tags and attributes in the allowlist are kept.
+    #[cfg(feature = "sanitize")]
+    Sanitized(html::Allowlist),
+}
+
 /// Anki note field.
 #[derive(Debug)]
 pub struct Field {
@@ -102,6 +288,12 @@ pub struct Field {
     font: Option<String>,
     size: Option<i64>,
     rtl: bool,
+    content: FieldContent,
+    furigana: bool,
+    #[cfg(feature = "markdown")]
+    markdown: bool,
+    #[cfg(feature = "highlight")]
+    code: Option<String>,
 }
 
 impl Field {
@@ -112,6 +304,12 @@ impl Field {
             font: None,
             size: None,
             rtl: false,
+            content: FieldContent::Html,
+            furigana: false,
+            #[cfg(feature = "markdown")]
+            markdown: false,
+            #[cfg(feature = "highlight")]
+            code: None,
         }
     }
 
@@ -133,6 +331,77 @@ impl Field {
         self
     }
 
+    /// Set how the field's values are turned into HTML when the note is created. By default they are written as is.
+    pub fn content(mut self, content: FieldContent) -> Self {
+        self.content = content;
+        self
+    }
+
+    /// Set whether the field's values contain readings in the bracket notation used by Anki's furigana filters.
+    /// The readings are validated when the deck is written, see [`furigana::validate`].
+    pub fn furigana(mut self, furigana: bool) -> Self {
+        self.furigana = furigana;
+        self
+    }
+
+    /// Set whether the field's values are written in Markdown.
+    /// Markdown values are converted to HTML when the note is created, and raw HTML in them is escaped.
+    /// The converted HTML is then handled according to the field's [`FieldContent`], so it should not be plain text.
+    #[cfg(feature = "markdown")]
+    pub fn markdown(mut self, markdown: bool) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    /// Set the field to contain code in the given language, which is highlighted when the note is created.
+    /// The language can be a name like "Rust" or a file extension like "rs".
+    /// If the field is also [Markdown](Field::markdown), only its code blocks are highlighted,
+    /// in the language given after the opening fence or in this language if there is none.
+    /// The highlighting is configured on the model with [`Model::highlighting`].
+    /// The highlighted HTML is then handled according to the field's [`FieldContent`], so it should not be plain text.
+    #[cfg(feature = "highlight")]
+    pub fn code(mut self, language: String) -> Self {
+        self.code = Some(language);
+        self
+    }
+
+    // converts a value of this field into the HTML that is written into the note
+    fn process_value(&self, value: String, model: &Model) -> String {
+        let value = self.convert(value, model);
+        match &self.content {
+            FieldContent::Html => value,
+            FieldContent::PlainText => html::escape(&value),
+            #[cfg(feature = "sanitize")]
+            FieldContent::Sanitized(allowlist) => allowlist.sanitize(&value),
+        }
+    }
+
+    // converts Markdown to HTML and highlights code, which is limited to the code blocks in Markdown
+    #[cfg_attr(not(feature = "highlight"), allow(unused_variables))]
+    fn convert(&self, value: String, model: &Model) -> String {
+        #[cfg(feature = "markdown")]
+        if self.markdown {
+            #[cfg(feature = "highlight")]
+            if let Some(default_language) = &self.code {
+                let highlight = |language: &str, code: &str| {
+                    let language = if language.is_empty() {
+                        default_language
+                    } else {
+                        language
+                    };
+                    model.highlighting.highlight(language, code)
+                };
+                return markdown::to_html(&value, Some(&highlight));
+            }
+            return markdown::to_html(&value, None);
+        }
+        #[cfg(feature = "highlight")]
+        if let Some(language) = &self.code {
+            return model.highlighting.highlight(language, &value);
+        }
+        value
+    }
+
     fn to_anki_json(&self) -> Value {
         serde_json::json!({
             // field name
@@ -169,6 +438,21 @@ impl Template {
         }
     }
 
+    /// Creates a new Template with an id derived from the namespace and name, see [`id::template_id`].
+    pub fn from_name(
+        namespace: &str,
+        name: String,
+        question_template: String,
+        answer_template: String,
+    ) -> Self {
+        Self::new(
+            id::template_id(namespace, &name),
+            name,
+            question_template,
+            answer_template,
+        )
+    }
+
     fn to_anki_json(&self) -> Value {
         serde_json::json!({
             // template name
@@ -207,6 +491,11 @@ pub struct Model {
     sort_field: i64,
     css: String,
     model_type: ModelType,
+    assets: Vec<Media>,
+    // (font family, asset name)
+    fonts: Vec<(String, String)>,
+    #[cfg(feature = "highlight")]
+    highlighting: highlight::Highlighting,
 }
 
 impl Model {
@@ -226,14 +515,80 @@ impl Model {
             sort_field,
             css,
             model_type,
+            assets: Vec::new(),
+            fonts: Vec::new(),
+            #[cfg(feature = "highlight")]
+            highlighting: highlight::Highlighting::default(),
         }
     }
 
+    /// Creates a new Model with an id derived from the namespace and name, see [`id::model_id`].
+    pub fn from_name(
+        namespace: &str,
+        name: String,
+        fields: Vec<Field>,
+        sort_field: i64,
+        css: String,
+        model_type: ModelType,
+    ) -> Self {
+        Self::new(
+            id::model_id(namespace, &name),
+            name,
+            fields,
+            sort_field,
+            css,
+            model_type,
+        )
+    }
+
+    /// Set how the model's code fields are highlighted, see [`Field::code`].
+    #[cfg(feature = "highlight")]
+    pub fn highlighting(mut self, highlighting: highlight::Highlighting) -> Self {
+        self.highlighting = highlighting;
+        self
+    }
+
+    /// Add a media file that is bundled with every package that uses the model, such as a JavaScript library that the templates include
+    /// with `<script src="_library.js"></script>`.
+    /// The name should start with an underscore so that Anki keeps the file even though no field refers to it.
+    /// Files starting with an underscore are written as they are, without being renamed, converted or merged with files with the same content,
+    /// so that references to them in templates keep working.
+    pub fn asset(mut self, name: String, data: Vec<u8>) -> Self {
+        self.assets.push(Media { name, data });
+        self
+    }
+
+    /// Add a font file as an asset and a `@font-face` rule to the model's CSS that loads it as the given font family,
+    /// which can then be used in the CSS like `font-family: "family";`.
+    /// The name should start with an underscore, see [`Model::asset`].
+    pub fn font(self, family: String, name: String, data: Vec<u8>) -> Self {
+        let mut model = self.asset(name.clone(), data);
+        model.fonts.push((family, name));
+        model
+    }
+
+    // the model's CSS, including the CSS needed by its fields and fonts
+    fn full_css(&self) -> String {
+        let mut css = self.css.clone();
+        for (family, name) in &self.fonts {
+            css.push_str(&format!(
+                "\n@font-face {{\n  font-family: \"{family}\";\n  src: url(\"{name}\");\n}}\n"
+            ));
+        }
+        #[cfg(feature = "highlight")]
+        if self.fields.iter().any(|f| f.code.is_some()) {
+            css.push('\n');
+            css.push_str(&self.highlighting.css());
+        }
+        css
+    }
+
     fn to_anki_json<'a, I: Iterator<Item = &'a Template>>(
         &self,
         deck_id: i64,
         templates: I,
         model_timestamp: i64,
+        media: &MediaFiles,
     ) -> Value {
         let fields = self
             .fields
@@ -262,76 +617,182 @@ impl Model {
             // fields json array
             "flds": fields,
             // CSS
-            "css": self.css,
+            "css": media.rewrite_css(&self.full_css()),
         })
     }
 }
 
+/// Review state for the cards of a note, used when the deck is written with scheduling included.
+#[derive(Debug, Clone, Copy)]
+pub struct Scheduling {
+    due_in_days: i64,
+    interval: i64,
+    ease_factor: i64,
+    reps: i64,
+    lapses: i64,
+}
+
+impl Scheduling {
+    /// Creates a review state for cards that are due in `due_in_days` days with the given interval in days.
+    /// The ease factor is in permille, Anki's default being 2500.
+    pub fn review(due_in_days: i64, interval: i64, ease_factor: i64) -> Self {
+        Self {
+            due_in_days,
+            interval,
+            ease_factor,
+            reps: 0,
+            lapses: 0,
+        }
+    }
+
+    /// Set the number of times the cards have been reviewed.
+    pub fn reps(mut self, reps: i64) -> Self {
+        self.reps = reps;
+        self
+    }
+
+    /// Set the number of times the cards have been forgotten.
+    pub fn lapses(mut self, lapses: i64) -> Self {
+        self.lapses = lapses;
+        self
+    }
+}
+
 #[derive(Debug)]
 /// An Anki card.
 struct Card;
 
+/// A row in the cards table.
+#[derive(Debug, Insertable)]
+#[diesel(table_name = schema::cards)]
+struct CardRow {
+    // doesn't seem to be used for anything, possibly for some timestamp
+    id: i64,
+    // note id
+    nid: i64,
+    // deck id
+    did: i64,
+    // template index in the model json
+    ord: i64,
+    // modified timestamp
+    mod_: i64,
+    // "update sequence number"
+    usn: i64,
+    // model type
+    type_: i64,
+    // card position in the queue, 0 = new
+    queue: i64,
+    // for new cards, the order in which cards are studied starting from 1
+    due: i64,
+    // interval...
+    ivl: i64,
+    // ease factor
+    factor: i64,
+    // number of reviews
+    reps: i64,
+    // number of lapses
+    lapses: i64,
+    // reps left today and reps left until graduation
+    left: i64,
+    // original due for filtered decks
+    odue: i64,
+    // original deck id for filtered decks
+    odid: i64,
+    // flag, none = 0, red = 1, orange = 2, green = 3, blue = 4
+    flags: i64,
+    // unused
+    data: &'static str,
+}
+
+impl CardRow {
+    // the number of columns in the cards table
+    const COLUMNS: usize = 18;
+}
+
 impl Card {
-    fn write_to_db(
+    fn to_row(
         template_ord: i64,
         card_ord: u16,
         note_id: i64,
         model: &Model,
         deck: &Deck,
-        conn: &mut SqliteConnection,
         card_id_timestamp: i64,
-    ) -> Result<(), Error> {
-        use schema::cards;
-
-        let card_ord: i64 = card_ord.into();
-        diesel::insert_into(cards::table)
-            .values((
-                // doesn't seem to be used for anything, possibly for some timestamp
-                cards::id.eq(card_id_timestamp),
-                // note id
-                cards::nid.eq(note_id),
-                // deck id
-                cards::did.eq(deck.id),
-                // template index in the model json
-                cards::ord.eq(template_ord),
-                // modified timestamp
-                cards::mod_.eq(card_id_timestamp),
-                // "update sequence number"
-                cards::usn.eq(0),
-                // model type
-                cards::type_.eq(model.model_type.to_anki_json_format()),
-                // card position in the queue, 0 = new
-                cards::queue.eq(0),
-                // for new cards, the order in which cards are studied starting from 1
-                cards::due.eq(card_ord),
-                // interval...
-                cards::ivl.eq(0),
-                // ease factor
-                cards::factor.eq(0),
-                // number of reviews
-                cards::reps.eq(0),
-                // number of lapses
-                cards::lapses.eq(0),
-                // reps left today and reps left until graduation
-                cards::left.eq(0),
-                // original due for filtered decks
-                cards::odue.eq(0),
-                // original deck id for filtered decks
-                cards::odid.eq(0),
-                // flag, none = 0, red = 1, orange = 2, green = 3, blue = 4
-                cards::flags.eq(0),
-                // unused
-                cards::data.eq(""),
-            ))
-            .execute(conn)
-            .map_err(error!(Error::Diesel, "Failed to insert into cards"))?;
+        scheduling: Option<&Scheduling>,
+    ) -> CardRow {
+        if let Some(scheduling) = scheduling {
+            // the collection is created today, so the due day is relative to today
+            return CardRow {
+                id: card_id_timestamp,
+                nid: note_id,
+                did: deck.id,
+                ord: template_ord,
+                mod_: card_id_timestamp,
+                usn: 0,
+                // review card
+                type_: 2,
+                // review queue
+                queue: 2,
+                due: scheduling.due_in_days,
+                ivl: scheduling.interval,
+                factor: scheduling.ease_factor,
+                reps: scheduling.reps,
+                lapses: scheduling.lapses,
+                left: 0,
+                odue: 0,
+                odid: 0,
+                flags: 0,
+                data: "",
+            };
+        }
 
-        Ok(())
+        CardRow {
+            id: card_id_timestamp,
+            nid: note_id,
+            did: deck.id,
+            ord: template_ord,
+            mod_: card_id_timestamp,
+            usn: 0,
+            type_: model.model_type.to_anki_json_format(),
+            queue: 0,
+            due: card_ord.into(),
+            ivl: 0,
+            factor: 0,
+            reps: 0,
+            lapses: 0,
+            left: 0,
+            odue: 0,
+            odid: 0,
+            flags: 0,
+            data: "",
+        }
     }
 }
 
+/// A row in the notes table.
+#[derive(Debug, Insertable)]
+#[diesel(table_name = schema::notes)]
+struct NoteRow<'a> {
+    // doesn't seem to be used for anything except the created timestamp for notes
+    id: i64,
+    guid: &'a str,
+    mid: i64,
+    mod_: i64,
+    usn: i64,
+    tags: String,
+    flds: String,
+    sfld: String,
+    csum: i64,
+    flags: i64,
+    data: &'static str,
+}
+
+impl NoteRow<'_> {
+    // the number of columns in the notes table
+    const COLUMNS: usize = 11;
+}
+
 /// An Anki note.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Note {
     guid: String,
     model: Arc<Model>,
@@ -339,17 +800,26 @@ pub struct Note {
     templates: Vec<Arc<Template>>,
     field_values: Vec<String>,
     card_ord: u16,
+    scheduling: Option<Scheduling>,
 }
 
 impl Note {
     /// Create a new Anki note.
-    ///  The `guid` should be unique and should not change.
+    ///  The `guid` should be unique and should not change. See the [`guid`] module for generating guids in Anki's format.
     pub fn new(
         guid: String,
         model: Arc<Model>,
         templates: Vec<Arc<Template>>,
         field_values: Vec<String>,
     ) -> Self {
+        let field_values = field_values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| match model.fields.get(i) {
+                Some(field) => field.process_value(value, &model),
+                None => value,
+            })
+            .collect();
         Self {
             guid,
             model,
@@ -357,9 +827,12 @@ impl Note {
             templates,
             field_values,
             card_ord: 1,
+            scheduling: None,
         }
     }
 
+    /// Set the note's tags. The tags are validated when the deck is written, see [`tags::validate`] and [`tags::normalize`].
+    /// They should already be in the form Anki normalises them into, including Unicode NFC.
     pub fn tags(mut self, tags: Vec<String>) -> Self {
         self.tags = Some(tags);
         self
@@ -371,56 +844,250 @@ impl Note {
         self
     }
 
-    // writes the note into a sqlite db
-    fn write_to_db(
+    /// Set the review state of the note's cards. Only written when scheduling is included in the [`WriteOptions`],
+    /// otherwise the cards are new.
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = Some(scheduling);
+        self
+    }
+
+    // the sort field's text, which Anki uses for sorting in the browser
+    fn sort_field(&self, field_values: &[Cow<str>]) -> String {
+        let sort_field = usize::try_from(self.model.sort_field)
+            .ok()
+            .and_then(|i| field_values.get(i))
+            .map(Cow::as_ref)
+            .unwrap_or_default();
+        html::strip_html_preserving_media_filenames(sort_field)
+    }
+
+    // the checksum of the first field's text, which Anki uses to find duplicates
+    fn checksum(field_values: &[Cow<str>]) -> i64 {
+        let first_field = field_values.first().map(Cow::as_ref).unwrap_or_default();
+        let text = html::strip_html_preserving_media_filenames(first_field);
+        let hash = Sha1::digest(text.as_bytes());
+        i64::from(u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]))
+    }
+
+    // converts the note into a notes row and its cards into cards rows
+    fn to_rows(
         &self,
         deck: &Deck,
-        templates: &HashMap<i64, (i64, Arc<Template>)>,
-        conn: &mut SqliteConnection,
-        timestamp_secs: i64,
-        note_id_timestamp: i64,
-        card_id_timestamp: &mut i64,
-    ) -> Result<(), Error> {
-        use schema::notes;
-
-        let fields = self.field_values.join("\x1f");
+        templates: &TemplateMap,
+        ids: &mut Ids,
+        include_scheduling: bool,
+        media: &MediaFiles,
+    ) -> Result<(NoteRow<'_>, Vec<CardRow>), Error> {
+        for (field, value) in self.model.fields.iter().zip(&self.field_values) {
+            if field.furigana {
+                furigana::validate(value)?;
+            }
+        }
+        // references to media files that were renamed when they were gathered are rewritten
+        let field_values = self
+            .field_values
+            .iter()
+            .map(|value| media.rewrite_field(value))
+            .collect::<Vec<_>>();
+        let fields = field_values.join("\x1f");
+        for tag in self.tags.iter().flatten() {
+            tags::validate(tag)?;
+        }
         let tags = self.tags.as_ref().map(|t| t.join(" ")).unwrap_or_default();
+        let (note_id, note_mod) = ids.note(&self.guid, &fields, &tags);
 
-        let note_id = diesel::insert_into(notes::table)
-            .values((
-                // doesn't seem to be used for anything except the created timestamp for notes
-                notes::id.eq(note_id_timestamp),
-                notes::guid.eq(&self.guid),
-                notes::mid.eq(self.model.id),
-                notes::mod_.eq(timestamp_secs),
-                notes::usn.eq(0),
-                notes::tags.eq(tags),
-                notes::flds.eq(fields),
-                notes::sfld.eq(0),
-                notes::csum.eq(0),
-                notes::flags.eq(0),
-                notes::data.eq(""),
-            ))
-            .returning(notes::id)
-            .get_result::<Option<i64>>(conn)
-            .map_err(error!(Error::Diesel, "Failed to insert note"))?
-            .expect("Did not receive an id back from the database after an insert");
-
+        let mut cards = Vec::with_capacity(self.templates.len());
         for template in &self.templates {
             let template_ord = templates.get(&template.id).map(|t| t.0).unwrap_or_default();
-            *card_id_timestamp += 1;
-            Card::write_to_db(
+            cards.push(Card::to_row(
                 template_ord,
                 self.card_ord,
                 note_id,
                 &self.model,
                 deck,
-                conn,
-                *card_id_timestamp,
-            )?;
+                ids.card(&self.guid, template.id),
+                self.scheduling.as_ref().filter(|_| include_scheduling),
+            ));
         }
 
-        Ok(())
+        let note = NoteRow {
+            id: note_id,
+            guid: &self.guid,
+            mid: self.model.id,
+            mod_: note_mod,
+            usn: 0,
+            tags,
+            flds: fields,
+            sfld: self.sort_field(&field_values),
+            csum: Self::checksum(&field_values),
+            flags: 0,
+            data: "",
+        };
+        Ok((note, cards))
+    }
+}
+
+/// Compression used for a file in the apkg zip archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Store the file without compressing it.
+    Stored,
+    /// Deflate with the given level, or the default level if `None`.
+    /// Levels 0 to 9 use regular deflate, levels 10 to 264 use the slower but denser zopfli.
+    Deflated(Option<i64>),
+    /// Zstandard with the given level, or the default level if `None`.
+    /// Note that Anki only reads deflated or stored files, so this is only useful for archives that are not imported directly.
+    Zstd(Option<i64>),
+}
+
+impl Compression {
+    fn to_file_options(self) -> SimpleFileOptions {
+        let (method, level) = match self {
+            Self::Stored => (CompressionMethod::Stored, None),
+            Self::Deflated(level) => (CompressionMethod::Deflated, level),
+            Self::Zstd(level) => (CompressionMethod::Zstd, level),
+        };
+        SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+    }
+}
+
+/// Options for writing a deck.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    collection: Compression,
+    media: Compression,
+    store_compressed_media: bool,
+    include_scheduling: bool,
+    record_deletions: bool,
+    require_media: bool,
+    normalize_media_names: bool,
+    check_media: bool,
+    #[cfg(feature = "images")]
+    image_processing: Option<images::ImageProcessing>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            collection: Compression::Deflated(None),
+            media: Compression::Deflated(None),
+            store_compressed_media: true,
+            include_scheduling: false,
+            record_deletions: false,
+            require_media: false,
+            normalize_media_names: true,
+            check_media: true,
+            #[cfg(feature = "images")]
+            image_processing: None,
+        }
+    }
+}
+
+impl WriteOptions {
+    /// Creates the default options, which deflate everything except media files that are already compressed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the compression for the collection database.
+    pub fn collection_compression(mut self, compression: Compression) -> Self {
+        self.collection = compression;
+        self
+    }
+
+    /// Set the compression for media files.
+    pub fn media_compression(mut self, compression: Compression) -> Self {
+        self.media = compression;
+        self
+    }
+
+    /// If set, media files in an already compressed format like JPEG, MP3 or OGG are stored without recompressing them,
+    /// regardless of the media compression.
+    pub fn store_compressed_media(mut self, store_compressed_media: bool) -> Self {
+        self.store_compressed_media = store_compressed_media;
+        self
+    }
+
+    /// If set, notes with [`Scheduling`] are written as review cards instead of new cards.
+    pub fn include_scheduling(mut self, include_scheduling: bool) -> Self {
+        self.include_scheduling = include_scheduling;
+        self
+    }
+
+    /// If set, notes that were in the previous build but have since been removed are recorded as deleted
+    /// when writing an update with a [`BuildState`].
+    pub fn record_deletions(mut self, record_deletions: bool) -> Self {
+        self.record_deletions = record_deletions;
+        self
+    }
+
+    /// If set, media files that are referred to in fields or CSS but can't be found are an error instead of a warning.
+    /// See [`Deck::media_root`] for how referred media files are found.
+    pub fn require_media(mut self, require_media: bool) -> Self {
+        self.require_media = require_media;
+        self
+    }
+
+    /// If set, media file names are normalised the same way Anki does when it imports them, and references to them are updated to match.
+    /// Otherwise names that Anki would change are an error. Set by default.
+    ///
+    /// Anki normalises names to NFC, removes the characters `[]<>:"/?*^\|` and control characters,
+    /// avoids names that are reserved on Windows and shortens names longer than 120 bytes.
+    pub fn normalize_media_names(mut self, normalize_media_names: bool) -> Self {
+        self.normalize_media_names = normalize_media_names;
+        self
+    }
+
+    /// If set, the formats of media files are detected from their contents, and files that are empty,
+    /// in a format Anki can't display, or whose contents don't match their extension are an error. Set by default.
+    ///
+    /// Only common image, audio, video and font formats are recognised, so files with other extensions are not checked against their contents.
+    pub fn check_media(mut self, check_media: bool) -> Self {
+        self.check_media = check_media;
+        self
+    }
+
+    /// Set how images are resized and re-encoded before they are written.
+    #[cfg(feature = "images")]
+    pub fn process_images(mut self, image_processing: images::ImageProcessing) -> Self {
+        self.image_processing = Some(image_processing);
+        self
+    }
+
+    fn media_file_options(&self, media: &Media) -> SimpleFileOptions {
+        if self.store_compressed_media && media.is_compressed() {
+            Compression::Stored.to_file_options()
+        } else {
+            self.media.to_file_options()
+        }
+    }
+}
+
+/// A media file such as an image or a sound that can be referred to in fields.
+#[derive(Debug, Clone)]
+struct Media {
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Media {
+    // file extensions for formats that are already compressed and barely shrink when compressed again
+    const COMPRESSED_EXTENSIONS: &[&str] = &[
+        "jpg", "jpeg", "png", "gif", "webp", "avif", "mp3", "ogg", "oga", "opus", "m4a", "aac",
+        "flac", "mp4", "m4v", "webm", "mkv", "woff", "woff2", "zip", "gz",
+    ];
+
+    fn is_compressed(&self) -> bool {
+        self.name
+            .rsplit_once('.')
+            .map(|(_, extension)| {
+                Self::COMPRESSED_EXTENSIONS
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(extension))
+            })
+            .unwrap_or_default()
     }
 }
 
@@ -435,10 +1102,20 @@ pub struct Deck {
     notes: Vec<Note>,
     // model id => template id => (template ord, template)
     model_to_templates: HashMap<i64, (Arc<Model>, TemplateMap)>,
+    media: Vec<Media>,
+    media_root: Option<PathBuf>,
+    config: Option<Arc<DeckConfig>>,
+    markdown_description: bool,
+    collapsed: bool,
+    browser_collapsed: bool,
+    new_limit: Option<u32>,
+    review_limit: Option<u32>,
 }
 
 impl Deck {
     /// Create a new deck. Note that the deck id 1 is special and corresponds to the default deck.
+    /// [`Deck::from_name`] avoids having to pick an id by hand.
+    /// The description is HTML that is shown on the deck's overview screen before studying it.
     pub fn new(id: i64, name: String, description: String) -> Self {
         Self {
             id,
@@ -446,9 +1123,68 @@ impl Deck {
             description,
             notes: Vec::new(),
             model_to_templates: HashMap::new(),
+            media: Vec::new(),
+            media_root: None,
+            config: None,
+            markdown_description: false,
+            collapsed: false,
+            browser_collapsed: false,
+            new_limit: None,
+            review_limit: None,
         }
     }
 
+    /// Create a new deck with an id derived from the namespace and name, see [`id::deck_id`].
+    pub fn from_name(namespace: &str, name: String, description: String) -> Self {
+        Self::new(id::deck_id(namespace, &name), name, description)
+    }
+
+    /// Set the options group of the deck. Decks without one get a generated options group in apkg files
+    /// and the default options group in colpkg files.
+    pub fn config(mut self, config: Arc<DeckConfig>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Set the directory that media files are read from when they are referred to in the deck's fields or its models' CSS,
+    /// for example with `<img src="name.jpg">`, `[sound:name.mp3]` or `url("name.woff2")`, but have not been added with [`Deck::add_media`].
+    pub fn media_root(mut self, media_root: PathBuf) -> Self {
+        self.media_root = Some(media_root);
+        self
+    }
+
+    /// Set whether the description is Markdown, which Anki renders and cleans of unsafe HTML before showing it.
+    pub fn markdown_description(mut self, markdown_description: bool) -> Self {
+        self.markdown_description = markdown_description;
+        self
+    }
+
+    /// Set whether the deck's subdecks are hidden in the deck list.
+    /// Decks are expanded by default, while earlier versions always wrote them collapsed, so set this to keep the old behaviour.
+    pub fn collapsed(mut self, collapsed: bool) -> Self {
+        self.collapsed = collapsed;
+        self
+    }
+
+    /// Set whether the deck's subdecks are hidden in the browser sidebar.
+    /// Decks are expanded by default, while earlier versions always wrote them collapsed, so set this to keep the old behaviour.
+    pub fn browser_collapsed(mut self, browser_collapsed: bool) -> Self {
+        self.browser_collapsed = browser_collapsed;
+        self
+    }
+
+    /// Set the maximum number of new cards introduced per day from this deck, overriding its options group.
+    pub fn new_limit(mut self, new_limit: u32) -> Self {
+        self.new_limit = Some(new_limit);
+        self
+    }
+
+    /// Set the maximum number of reviews per day from this deck, overriding its options group.
+    pub fn review_limit(mut self, review_limit: u32) -> Self {
+        self.review_limit = Some(review_limit);
+        self
+    }
+
     /// Add a note to the deck.
     pub fn add_note(&mut self, note: Note) {
         let (_model, template_map) = self
@@ -467,99 +1203,397 @@ impl Deck {
         self.notes.push(note);
     }
 
+    /// Add a media file to the deck. Fields can refer to it by its name, for example with `<img src="name.jpg">` or `[sound:name.mp3]`.
+    pub fn add_media(&mut self, name: String, data: Vec<u8>) {
+        self.media.push(Media { name, data });
+    }
+
+    /// Gathers the deck's media files like when it is written and summarises them.
+    pub fn media_report(&self, options: &WriteOptions) -> Result<MediaReport, Error> {
+        media::collect(&[self], options).map(|media| media.report())
+    }
+
     /// Write the deck into the writer in the apkg format.
     pub fn write<W: Write + Seek>(&self, writer: W) -> Result<(), Error> {
-        // write to sqlite db
-        let mut conn = SqliteConnection::establish(":memory:").map_err(error!(
-            Error::DieselConn,
-            "Failed to establish connection to in-memory sqlite database"
-        ))?;
-
-        conn.exclusive_transaction(move |tx| {
-            tx.run_pending_migrations(MIGRATIONS).map_err({
-                error!(
-                    Error::Generic,
-                    "Failed to run migrations for in-memory sqlite database"
-                )
-            })?;
-            self.write_to_db(tx)?;
-            Result::<(), Error>::Ok(())
+        self.write_with_options(writer, &WriteOptions::default())
+    }
+
+    /// Write the deck into the writer in the apkg format with the given options.
+    pub fn write_with_options<W: Write + Seek>(
+        &self,
+        writer: W,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        write_package(&self.contents(), PackageKind::Apkg, writer, options, None)
+    }
+
+    /// Write the deck into the writer in the apkg format as an update to the build recorded in `state`.
+    /// Notes keep the ids they had in the previous build and are only marked as modified if their fields or tags have changed,
+    /// so that importing the update only touches the notes that were actually changed.
+    /// Once the package has been written, the state is updated to match this build and should be saved for the next one.
+    /// If writing fails, the state is left as it was.
+    pub fn write_update<W: Write + Seek>(
+        &self,
+        writer: W,
+        state: &mut BuildState,
+    ) -> Result<(), Error> {
+        self.write_update_with_options(writer, state, &WriteOptions::default())
+    }
+
+    /// Write the deck into the writer in the apkg format as an update to the build recorded in `state` with the given options.
+    pub fn write_update_with_options<W: Write + Seek>(
+        &self,
+        writer: W,
+        state: &mut BuildState,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        write_package(
+            &self.contents(),
+            PackageKind::Apkg,
+            writer,
+            options,
+            Some(state),
+        )
+    }
+
+    // the deck on its own with the default collection config
+    fn contents(&self) -> Contents<'_> {
+        Contents {
+            decks: vec![self],
+            filtered_decks: &[],
+            config: Cow::Owned(CollectionConfig::default()),
+        }
+    }
+
+    fn to_value(&self, conf_id: i64, timestamp_millis: i64) -> Value {
+        let mut value = DeckJson {
+            id: self.id,
+            name: &self.name,
+            description: &self.description,
+            markdown_description: self.markdown_description,
+            collapsed: self.collapsed,
+            browser_collapsed: self.browser_collapsed,
+            filtered: false,
+        }
+        .to_map(timestamp_millis);
+        value.extend([
+            // the key of the corresponding deck config in the dconf JSON in the col table
+            ("conf".to_string(), Value::from(conf_id)),
+            // extra new cards for today from custom study
+            ("extendNew".to_string(), Value::from(0)),
+            // extra reviews for today from custom study
+            ("extendRev".to_string(), Value::from(0)),
+        ]);
+        // limits that override the options group, left out when not set like Anki does
+        if let Some(new_limit) = self.new_limit {
+            value.insert("newLimit".to_string(), new_limit.into());
+        }
+        if let Some(review_limit) = self.review_limit {
+            value.insert("reviewLimit".to_string(), review_limit.into());
+        }
+        Value::Object(value)
+    }
+}
+
+// the keys shared by normal and filtered decks in the decks JSON
+struct DeckJson<'a> {
+    id: i64,
+    name: &'a str,
+    description: &'a str,
+    markdown_description: bool,
+    collapsed: bool,
+    browser_collapsed: bool,
+    filtered: bool,
+}
+
+impl DeckJson<'_> {
+    fn to_map(&self, timestamp_millis: i64) -> Map<String, Value> {
+        let mut map = Map::from_iter([
+            // deck id
+            ("id".to_string(), Value::from(self.id)),
+            // modified timestamp
+            ("mod".to_string(), Value::from(timestamp_millis)),
+            // deck name
+            ("name".to_string(), Value::from(self.name)),
+            // "update sequence number"
+            ("usn".to_string(), Value::from(0)),
+            // the studied counts as [day, count], where day is the number of days since the collection was created
+            // and the count is reset when it's not today, so that a new deck starts with nothing studied
+            ("lrnToday".to_string(), serde_json::json!([0, 0])),
+            ("revToday".to_string(), serde_json::json!([0, 0])),
+            ("newToday".to_string(), serde_json::json!([0, 0])),
+            // milliseconds spent studying, as [day, milliseconds]
+            ("timeToday".to_string(), serde_json::json!([0, 0])),
+            // whether the subdecks are hidden in the deck list
+            ("collapsed".to_string(), Value::from(self.collapsed)),
+            // whether the subdecks are hidden in the browser sidebar
+            (
+                "browserCollapsed".to_string(),
+                Value::from(self.browser_collapsed),
+            ),
+            // deck description
+            ("desc".to_string(), Value::from(self.description)),
+            // unfiltered (standard) deck = 0, filtered deck = 1
+            ("dyn".to_string(), Value::from(i64::from(self.filtered))),
+        ]);
+        // whether the description is markdown, left out when false like Anki does
+        if self.markdown_description {
+            map.insert("md".to_string(), Value::from(true));
+        }
+        map
+    }
+}
+
+// the decks and settings written into a package
+struct Contents<'a> {
+    decks: Vec<&'a Deck>,
+    filtered_decks: &'a [FilteredDeck],
+    config: Cow<'a, CollectionConfig>,
+}
+
+// the kind of package being written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PackageKind {
+    // a deck package that is merged into the user's collection
+    Apkg,
+    // a full collection that replaces the user's collection
+    Colpkg,
+}
+
+// writes the decks into the writer as a package
+fn write_package<W: Write + Seek>(
+    contents: &Contents,
+    kind: PackageKind,
+    writer: W,
+    options: &WriteOptions,
+    state: Option<&mut BuildState>,
+) -> Result<(), Error> {
+    let media = media::collect(&contents.decks, options)?;
+    let report = media.report();
+    if !report.duplicates.is_empty() {
+        tracing::info!(
+            "Skipped {} media files with the same content as another file, saving {} bytes",
+            report.duplicates.len(),
+            report.saved_bytes
+        );
+    }
+
+    // write to sqlite db
+    let mut conn = SqliteConnection::establish(":memory:").map_err(error!(
+        Error::DieselConn,
+        "Failed to establish connection to in-memory sqlite database"
+    ))?;
+
+    let media_files = &media;
+    let previous_state = state.as_deref();
+    let new_state = conn.exclusive_transaction(move |tx| {
+        tx.run_pending_migrations(MIGRATIONS).map_err({
+            error!(
+                Error::Generic,
+                "Failed to run migrations for in-memory sqlite database"
+            )
         })?;
-        let buf = conn.serialize_database_to_buffer();
+        write_to_db(contents, kind, options, previous_state, media_files, tx)
+    })?;
+    let buf = conn.serialize_database_to_buffer();
+
+    // write zip
+    let mut zip = ZipWriter::new(writer);
+    zip.start_file("collection.anki2", options.collection.to_file_options())
+        .map_err(error!(Error::Zip, "Failed to start file in zip archive"))?;
+    zip.write_all(buf.as_slice()).map_err(error!(
+        Error::Io,
+        "Failed to write anki collection into zip"
+    ))?;
 
-        // write zip
-        let mut zip = ZipWriter::new(writer);
-        zip.start_file("collection.anki2", SimpleFileOptions::default())
+    // media files are stored as 0, 1, 2... and the media file maps these back to their names
+    let mut media_map = Map::new();
+    for (i, media) in media.files.iter().enumerate() {
+        let zip_name = i.to_string();
+        zip.start_file(zip_name.as_str(), options.media_file_options(media))
             .map_err(error!(Error::Zip, "Failed to start file in zip archive"))?;
-        zip.write_all(buf.as_slice()).map_err(error!(
-            Error::Io,
-            "Failed to write anki collection into zip"
-        ))?;
-        Ok(())
+        zip.write_all(&media.data)
+            .map_err(error!(Error::Io, "Failed to write media file into zip"))?;
+        media_map.insert(zip_name, Value::String(media.name.clone()));
     }
+    zip.start_file("media", options.media.to_file_options())
+        .map_err(error!(Error::Zip, "Failed to start file in zip archive"))?;
+    zip.write_all(Value::Object(media_map).to_string().as_bytes())
+        .map_err(error!(Error::Io, "Failed to write media map into zip"))?;
+    zip.finish()
+        .map_err(error!(Error::Zip, "Failed to finish zip archive"))?;
 
-    // writes the deck into a sqlite db
-    fn write_to_db(&self, conn: &mut SqliteConnection) -> Result<(), Error> {
-        let timestamp = UNIX_EPOCH.elapsed().unwrap();
-        let timestamp_secs = timestamp.as_secs() as i64;
-        let timestamp_millis = timestamp.as_millis() as i64;
-        let mut card_id_timestamp = timestamp_millis;
-
-        Col::write_to_db(self, conn, timestamp_secs, timestamp_millis)?;
-        let mut note_id_timestamp = timestamp_millis;
-        for note in &self.notes {
-            let (_m, model_templates) = self.model_to_templates.get(&note.model.id).unwrap();
-            note_id_timestamp += 1;
-            note.write_to_db(
-                self,
-                model_templates,
-                conn,
-                timestamp_secs,
-                note_id_timestamp,
-                &mut card_id_timestamp,
-            )?;
+    // the build state is only updated once the package has been written successfully
+    if let (Some(state), Some(new_state)) = (state, new_state) {
+        *state = new_state;
+    }
+    Ok(())
+}
+
+// writes the decks into a sqlite db and returns the new build state if there is a previous one
+fn write_to_db(
+    contents: &Contents,
+    kind: PackageKind,
+    options: &WriteOptions,
+    state: Option<&BuildState>,
+    media: &MediaFiles,
+    conn: &mut SqliteConnection,
+) -> Result<Option<BuildState>, Error> {
+    let timestamp = UNIX_EPOCH
+        .elapsed()
+        .map_err(|source| Error::Time { source })?;
+    let timestamp_secs = timestamp.as_secs() as i64;
+    let timestamp_millis = timestamp.as_millis() as i64;
+    let mut ids = Ids::new(state, timestamp_secs, timestamp_millis);
+
+    // a model can be used in several decks, so the template ords are merged in deck order
+    // model id => (model, template map, id of the first deck using the model)
+    let mut models = HashMap::<i64, (Arc<Model>, TemplateMap, i64)>::new();
+    for deck in &contents.decks {
+        for (model, deck_templates) in deck.model_to_templates.values() {
+            let (_model, template_map, _deck_id) = models
+                .entry(model.id)
+                .or_insert_with(|| (model.clone(), HashMap::new(), deck.id));
+            let mut deck_templates = deck_templates.values().collect::<Vec<_>>();
+            deck_templates.sort_by_key(|(ord, _t)| *ord);
+            for (_ord, template) in deck_templates {
+                let new_template_ord = template_map.len() as i64;
+                template_map
+                    .entry(template.id)
+                    .or_insert_with(|| (new_template_ord, template.clone()));
+            }
         }
-        Ok(())
     }
 
-    fn to_value(&self, timestamp_millis: i64) -> Value {
-        serde_json::json!(
-            {
-                self.id.to_string(): {
-                    // deck id
-                    "id": self.id,
-                    // modified timestamp
-                    "mod": timestamp_millis,
-                    // deck name
-                    "name": self.name,
-                    // "update sequence number"
-                    "usn": 0,
-                    // unsure
-                    "lrnToday": [0,0],
-                    // unsure
-                    "revToday": [0,0],
-                    // unsure
-                    "newToday": [0,0],
-                    // unsure
-                    "timeToday": [0,0],
-                    // unsure
-                    "collapsed": true,
-                    // unsure
-                    "browserCollapsed": true,
-                    // deck description
-                    "desc": self.description,
-                    // unfiltered (standard) deck = 0, filtered deck = 1
-                    "dyn": 0,
-                    // the key of the corresponding deck config in the dconf JSON in the col table
-                    "conf": timestamp_millis,
-                    // custom study
-                    "extendNew": 0,
-                    // custom study
-                    "extendRev": 0,
-                }
+    Col::write_to_db(contents, &models, kind, media, conn, timestamp)?;
+
+    for (model, _templates, _deck_id) in models.values() {
+        ids.model(model)?;
+    }
+
+    // the ids are assigned up front so that the rows can be inserted in large batches
+    let mut notes = Vec::new();
+    let mut cards = Vec::new();
+    // the deck, index and note of each note row, and the index of the note row of each card row, for reporting errors
+    let mut note_origins = Vec::new();
+    let mut card_origins = Vec::new();
+    let mut guids = HashSet::new();
+    for deck in &contents.decks {
+        for (index, note) in deck.notes.iter().enumerate() {
+            let (note_row, note_cards) = models
+                .get(&note.model.id)
+                .ok_or(Error::MissingModel {
+                    model_id: note.model.id,
+                })
+                .and_then(|(_m, model_templates, _deck_id)| {
+                    // notes with the same guid would overwrite each other when imported
+                    if !guids.insert(note.guid.as_str()) {
+                        return Err(Error::DuplicateGuid {
+                            guid: note.guid.clone(),
+                        });
+                    }
+                    note.to_rows(
+                        deck,
+                        model_templates,
+                        &mut ids,
+                        options.include_scheduling,
+                        media,
+                    )
+                })
+                .map_err(|err| err.in_note(deck, index, note))?;
+            card_origins.extend(std::iter::repeat_n(notes.len(), note_cards.len()));
+            notes.push(note_row);
+            note_origins.push((deck, index, note));
+            cards.extend(note_cards);
+        }
+    }
+
+    let in_note = |row: Option<usize>, err: Error| match row.and_then(|row| note_origins.get(row)) {
+        Some((deck, index, note)) => err.in_note(deck, *index, note),
+        None => err,
+    };
+    insert_in_batches(&notes, NoteRow::COLUMNS, |chunk| {
+        diesel::insert_into(schema::notes::table)
+            .values(chunk)
+            .execute(conn)
+    })
+    .map_err(|(row, source)| {
+        let err = error!(Error::Diesel, "Failed to insert notes")(source);
+        in_note(row, err)
+    })?;
+    insert_in_batches(&cards, CardRow::COLUMNS, |chunk| {
+        diesel::insert_into(schema::cards::table)
+            .values(chunk)
+            .execute(conn)
+    })
+    .map_err(|(row, source)| {
+        let err = error!(Error::Diesel, "Failed to insert cards")(source);
+        in_note(row.and_then(|row| card_origins.get(row).copied()), err)
+    })?;
+
+    let (new_state, deleted) = ids.finish();
+    if options.record_deletions {
+        use schema::graves;
+
+        // type 0 = card, 1 = note
+        let (graves, grave_guids): (Vec<_>, Vec<_>) = deleted
+            .iter()
+            .flat_map(|note| {
+                note.card_ids
+                    .iter()
+                    .map(|card_id| (*card_id, 0))
+                    .chain([(note.id, 1)])
+                    .map(|(oid, type_)| {
+                        (
+                            (
+                                graves::usn.eq(0),
+                                graves::oid.eq(oid),
+                                graves::type_.eq(type_),
+                            ),
+                            note.guid.as_str(),
+                        )
+                    })
+            })
+            .unzip();
+        insert_in_batches(&graves, 3, |chunk| {
+            diesel::insert_into(graves::table)
+                .values(chunk)
+                .execute(conn)
+        })
+        .map_err(|(row, source)| {
+            let err = error!(Error::Diesel, "Failed to insert graves")(source);
+            match row.and_then(|row| grave_guids.get(row)) {
+                Some(guid) => Error::DeletedNote {
+                    guid: guid.to_string(),
+                    source: Box::new(err),
+                },
+                None => err,
             }
-        )
+        })?;
+    }
+    Ok(new_state)
+}
+
+// inserts the rows in batches, retrying a failed batch row by row to find the index of the row that caused the error
+fn insert_in_batches<R>(
+    rows: &[R],
+    columns: usize,
+    mut insert: impl FnMut(&[R]) -> QueryResult<usize>,
+) -> Result<(), (Option<usize>, diesel::result::Error)> {
+    let batch_size = SQLITE_MAX_VARIABLES / columns;
+    for (batch, chunk) in rows.chunks(batch_size).enumerate() {
+        let Err(err) = insert(chunk) else {
+            continue;
+        };
+        // a failed statement inserts none of its rows, and the transaction is rolled back after the error anyway
+        for (i, row) in chunk.iter().enumerate() {
+            if let Err(err) = insert(std::slice::from_ref(row)) {
+                return Err((Some(batch * batch_size + i), err));
+            }
+        }
+        return Err((None, err));
     }
+    Ok(())
 }
 
 /// Anki collection.
@@ -567,32 +1601,114 @@ struct Col;
 
 impl Col {
     fn write_to_db(
-        deck: &Deck,
+        contents: &Contents,
+        models: &HashMap<i64, (Arc<Model>, TemplateMap, i64)>,
+        kind: PackageKind,
+        media: &MediaFiles,
         conn: &mut SqliteConnection,
-        timestamp_secs: i64,
-        timestamp_millis: i64,
+        timestamp: Duration,
     ) -> Result<(), Error> {
         use schema::col;
 
-        let conf = serde_json::json!({});
-        let models = deck
-            .model_to_templates
+        let timestamp_secs = timestamp.as_secs() as i64;
+        let timestamp_millis = timestamp.as_millis() as i64;
+        let decks = &contents.decks;
+        let filtered_decks = contents.filtered_decks;
+
+        let conf = contents.config.to_anki_json(decks, filtered_decks);
+        let models = models
             .values()
-            .map(|(m, templates)| {
+            .map(|(m, templates, deck_id)| {
+                let mut templates = templates.values().collect::<Vec<_>>();
+                templates.sort_by_key(|(ord, _t)| *ord);
                 (
                     m.id.to_string(),
                     m.to_anki_json(
-                        deck.id,
-                        templates.values().map(|(_ord, t)| t.as_ref()),
+                        *deck_id,
+                        templates.into_iter().map(|(_ord, t)| t.as_ref()),
                         timestamp_millis,
+                        media,
                     ),
                 )
             })
             .collect::<Map<_, _>>();
         let models = Value::Object(models);
 
-        let dconf = Dconf::new(deck.id);
-        let tags = "{}";
+        // decks without a config use the default options group in a full collection,
+        // and a generated one in a deck package
+        let default_config = match kind {
+            PackageKind::Apkg => DeckConfig::new(timestamp_millis, "reanki-dconf".to_string()),
+            PackageKind::Colpkg => DeckConfig::new(1, "Default".to_string()),
+        };
+        let mut decks_json = Map::new();
+        let mut dconf = Map::new();
+        if kind == PackageKind::Colpkg {
+            // a full collection always has the default deck and options group
+            let default_deck = Deck::new(1, "Default".to_string(), String::new());
+            decks_json.insert(
+                default_deck.id.to_string(),
+                default_deck.to_value(default_config.id, timestamp_millis),
+            );
+            dconf.insert(
+                default_config.id.to_string(),
+                default_config.to_anki_json(timestamp_millis),
+            );
+        }
+        // deck id => name, decks with the same id would replace each other in the decks JSON
+        let mut deck_ids = HashMap::new();
+        let all_decks = decks
+            .iter()
+            .map(|d| (d.id, &d.name))
+            .chain(filtered_decks.iter().map(|d| (d.id, &d.name)));
+        for (id, name) in all_decks {
+            if let Some(first) = deck_ids.insert(id, name) {
+                return Err(Error::DuplicateDeckId {
+                    id,
+                    first: first.clone(),
+                    second: name.clone(),
+                });
+            }
+        }
+        for deck in decks {
+            let config = deck.config.as_deref().unwrap_or(&default_config);
+            decks_json.insert(
+                deck.id.to_string(),
+                deck.to_value(config.id, timestamp_millis),
+            );
+            dconf.insert(config.id.to_string(), config.to_anki_json(timestamp_millis));
+        }
+        for filtered_deck in filtered_decks {
+            filtered_deck.validate()?;
+            // Anki moves decks out of filtered decks, so they can't be parents
+            let prefix = format!("{}::", filtered_deck.name);
+            if decks
+                .iter()
+                .map(|d| &d.name)
+                .chain(filtered_decks.iter().map(|d| &d.name))
+                .any(|name| name.starts_with(&prefix))
+            {
+                return Err(Error::InvalidFilteredDeck {
+                    name: filtered_deck.name.clone(),
+                    reason: "filtered decks can't contain other decks",
+                });
+            }
+            decks_json.insert(
+                filtered_deck.id.to_string(),
+                filtered_deck.to_value(timestamp_millis),
+            );
+        }
+
+        // every tag used in the notes and their ancestors, so that they show up in the browser right away
+        let tags = tags::registry(
+            decks
+                .iter()
+                .flat_map(|d| &d.notes)
+                .flat_map(|n| n.tags.as_deref().unwrap_or_default())
+                .map(String::as_str),
+        )
+        .into_iter()
+        .map(|tag| (tag, Value::from(0)))
+        .collect::<Map<_, _>>();
 
         diesel::insert_into(col::table)
             .values((
@@ -617,14 +1733,407 @@ impl Col {
                 // models json
                 col::models.eq(models.to_string()),
                 // decks json
-                col::decks.eq(deck.to_value(timestamp_millis).to_string()),
+                col::decks.eq(Value::Object(decks_json).to_string()),
                 // deck config json
-                col::dconf.eq(dconf.to_anki_json(timestamp_millis).to_string()),
-                // tags, unsure
-                col::tags.eq(tags),
+                col::dconf.eq(Value::Object(dconf).to_string()),
+                // tags json, tag => usn
+                col::tags.eq(Value::Object(tags).to_string()),
             ))
             .execute(conn)
             .map_err(error!(Error::Diesel, "Failed to insert collection"))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{self, Cursor, Read, SeekFrom};
+
+    pub(crate) fn model() -> Arc<Model> {
+        Arc::new(Model::new(
+            1,
+            "Basic".to_string(),
+            vec![
+                Field::new("Front".to_string()),
+                Field::new("Back".to_string()),
+            ],
+            0,
+            String::new(),
+            ModelType::Standard,
+        ))
+    }
+
+    pub(crate) fn template() -> Arc<Template> {
+        Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            "{{Front}}".to_string(),
+            "{{Back}}".to_string(),
+        ))
+    }
+
+    fn deck(notes: &[(&str, &str)]) -> Deck {
+        let (model, template) = (model(), template());
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        for (guid, front) in notes {
+            deck.add_note(Note::new(
+                guid.to_string(),
+                model.clone(),
+                vec![template.clone()],
+                vec![front.to_string(), String::new()],
+            ));
+        }
+        deck
+    }
+
+    // a writer that fails after the given number of bytes
+    struct FailingWriter {
+        inner: Cursor<Vec<u8>>,
+        limit: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.inner.get_ref().len() + buf.len() > self.limit {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingWriter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    // opens the collection database in a written package
+    pub(crate) fn open_collection(package: Vec<u8>) -> SqliteConnection {
+        let mut zip = zip::ZipArchive::new(Cursor::new(package)).unwrap();
+        let mut buf = Vec::new();
+        zip.by_name("collection.anki2")
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        // the database borrows the buffer for as long as the connection is open
+        conn.deserialize_readonly_database_from_buffer(buf.leak())
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn notes_and_cards_are_written_in_batches() {
+        // enough notes for several inserts of notes and of cards
+        let count = SQLITE_MAX_VARIABLES / NoteRow::COLUMNS * 2 + 1;
+        let guids = (0..count).map(|i| i.to_string()).collect::<Vec<_>>();
+        let notes = guids
+            .iter()
+            .map(|guid| (guid.as_str(), guid.as_str()))
+            .collect::<Vec<_>>();
+        let mut package = Cursor::new(Vec::new());
+        deck(&notes).write(&mut package).unwrap();
+
+        let mut conn = open_collection(package.into_inner());
+        let note_ids = schema::notes::table
+            .select(schema::notes::id.assume_not_null())
+            .load::<i64>(&mut conn)
+            .unwrap()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let card_note_ids = schema::cards::table
+            .select(schema::cards::nid)
+            .load::<i64>(&mut conn)
+            .unwrap();
+        assert_eq!(note_ids.len(), count);
+        assert_eq!(card_note_ids.len(), count);
+        assert_eq!(card_note_ids.into_iter().collect::<HashSet<_>>(), note_ids);
+    }
+
+    #[test]
+    fn scheduling_is_only_written_when_included() {
+        let mut deck = deck(&[("new", "1")]);
+        deck.add_note(
+            Note::new(
+                "review".to_string(),
+                model(),
+                vec![template()],
+                vec!["2".to_string(), String::new()],
+            )
+            .scheduling(Scheduling::review(3, 10, 2300).reps(5).lapses(1)),
+        );
+        let cards = |options: &WriteOptions| {
+            let mut package = Cursor::new(Vec::new());
+            deck.write_with_options(&mut package, options).unwrap();
+            let mut conn = open_collection(package.into_inner());
+            schema::cards::table
+                .inner_join(
+                    schema::notes::table.on(schema::notes::id.eq(schema::cards::nid.nullable())),
+                )
+                .select((
+                    schema::notes::guid,
+                    (
+                        schema::cards::type_,
+                        schema::cards::queue,
+                        schema::cards::due,
+                        schema::cards::ivl,
+                        schema::cards::factor,
+                        schema::cards::reps,
+                        schema::cards::lapses,
+                    ),
+                ))
+                .load::<(String, (i64, i64, i64, i64, i64, i64, i64))>(&mut conn)
+                .unwrap()
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        };
+
+        let cards_without = cards(&WriteOptions::default());
+        assert_eq!(cards_without["review"], (0, 0, 1, 0, 0, 0, 0));
+
+        let cards_with = cards(&WriteOptions::default().include_scheduling(true));
+        assert_eq!(cards_with["new"], (0, 0, 1, 0, 0, 0, 0));
+        assert_eq!(cards_with["review"], (2, 2, 3, 10, 2300, 5, 1));
+    }
+
+    #[test]
+    fn collections_contain_every_deck() {
+        let mut collection = Collection::new();
+        collection.add_deck(deck(&[("a", "1")]));
+        let mut other = Deck::new(4, "Other".to_string(), String::new());
+        other.add_note(Note::new(
+            "b".to_string(),
+            model(),
+            vec![template()],
+            vec!["2".to_string(), String::new()],
+        ));
+        collection.add_deck(other);
+
+        let deck_names = |package: Vec<u8>| {
+            let mut conn = open_collection(package);
+            let decks = schema::col::table
+                .select(schema::col::decks)
+                .first::<String>(&mut conn)
+                .unwrap();
+            let decks = serde_json::from_str::<Map<String, Value>>(&decks).unwrap();
+            let mut names = decks
+                .values()
+                .map(|deck| deck["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            let notes = schema::notes::table
+                .count()
+                .get_result::<i64>(&mut conn)
+                .unwrap();
+            (names, notes)
+        };
+
+        let mut apkg = Cursor::new(Vec::new());
+        collection.write_apkg(&mut apkg).unwrap();
+        assert_eq!(
+            deck_names(apkg.into_inner()),
+            (vec!["Deck".to_string(), "Other".to_string()], 2)
+        );
+
+        // a full collection always has the default deck
+        let mut colpkg = Cursor::new(Vec::new());
+        collection.write_colpkg(&mut colpkg).unwrap();
+        assert_eq!(
+            deck_names(colpkg.into_inner()),
+            (
+                vec![
+                    "Deck".to_string(),
+                    "Default".to_string(),
+                    "Other".to_string()
+                ],
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn notes_with_guids_anki_accepts_are_written() {
+        let mut package = Cursor::new(Vec::new());
+        deck(&[("vocab 1", "1"), ("日本", "2")])
+            .write(&mut package)
+            .unwrap();
+    }
+
+    #[test]
+    fn model_assets_are_written_as_they_are() {
+        let png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let model = Arc::new(
+            Model::new(
+                1,
+                "Basic".to_string(),
+                vec![
+                    Field::new("Front".to_string()),
+                    Field::new("Back".to_string()),
+                ],
+                0,
+                String::new(),
+                ModelType::Standard,
+            )
+            .asset("_logo.png".to_string(), png.clone()),
+        );
+        let template = Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            r#"<img src="_logo.png">{{Front}}"#.to_string(),
+            "{{Back}}".to_string(),
+        ));
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        deck.add_note(Note::new(
+            "a".to_string(),
+            model,
+            vec![template],
+            vec![r#"<img src="pic.png">"#.to_string(), String::new()],
+        ));
+        deck.add_media("pic.png".to_string(), png);
+
+        let report = deck.media_report(&WriteOptions::default()).unwrap();
+        assert!(report.duplicates.is_empty(), "{report:?}");
+        let mut package = Cursor::new(Vec::new());
+        deck.write(&mut package).unwrap();
+        let mut zip = zip::ZipArchive::new(package).unwrap();
+        let mut media_map = String::new();
+        zip.by_name("media")
+            .unwrap()
+            .read_to_string(&mut media_map)
+            .unwrap();
+        let mut names = serde_json::from_str::<HashMap<String, String>>(&media_map)
+            .unwrap()
+            .into_values()
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["_logo.png", "pic.png"]);
+
+        // renaming them would break the references in templates
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        deck.add_media("_a:b.png".to_string(), b"\x89PNG\r\n\x1a\n".to_vec());
+        assert!(matches!(
+            deck.write(Cursor::new(Vec::new())),
+            Err(Error::InvalidMediaName { .. })
+        ));
+    }
+
+    // the decks JSON in the col table of a written package, by deck id
+    fn decks_json(package: Vec<u8>) -> Map<String, Value> {
+        let mut conn = open_collection(package);
+        let decks = schema::col::table
+            .select(schema::col::decks)
+            .first::<String>(&mut conn)
+            .unwrap();
+        serde_json::from_str(&decks).unwrap()
+    }
+
+    #[test]
+    fn deck_settings_are_written_into_the_decks_json() {
+        let mut package = Cursor::new(Vec::new());
+        deck(&[])
+            .markdown_description(true)
+            .collapsed(true)
+            .browser_collapsed(true)
+            .new_limit(10)
+            .review_limit(100)
+            .write(&mut package)
+            .unwrap();
+        let decks = decks_json(package.into_inner());
+        let json = &decks["3"];
+        assert_eq!(json["md"], true);
+        assert_eq!(json["collapsed"], true);
+        assert_eq!(json["browserCollapsed"], true);
+        assert_eq!(json["newLimit"], 10);
+        assert_eq!(json["reviewLimit"], 100);
+
+        // the keys Anki leaves out by default are left out as well, and decks are expanded
+        let mut package = Cursor::new(Vec::new());
+        deck(&[]).write(&mut package).unwrap();
+        let decks = decks_json(package.into_inner());
+        let json = decks["3"].as_object().unwrap();
+        assert_eq!(json["collapsed"], false);
+        assert_eq!(json["browserCollapsed"], false);
+        assert!(!json.contains_key("md"));
+        assert!(!json.contains_key("newLimit"));
+        assert!(!json.contains_key("reviewLimit"));
+    }
+
+    #[test]
+    fn failed_batch_insert_reports_the_row() {
+        let batch_size = SQLITE_MAX_VARIABLES / 2;
+        let failing = batch_size + 100;
+        let rows = (0..2 * batch_size).collect::<Vec<_>>();
+        let mut calls = 0;
+        let result = insert_in_batches(&rows, 2, |chunk| {
+            calls += 1;
+            if chunk.contains(&failing) {
+                Err(diesel::result::Error::NotFound)
+            } else {
+                Ok(chunk.len())
+            }
+        });
+        assert!(matches!(result, Err((Some(row), _)) if row == failing));
+        // two batches, then the 100 rows before the failing one in the second batch, then the failing row
+        assert_eq!(calls, 2 + 100 + 1);
+
+        let result = insert_in_batches(&rows, 2, |chunk| Ok(chunk.len()));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn failed_update_keeps_the_build_state() {
+        let mut state = BuildState::new();
+        deck(&[("a", "1")])
+            .write_update(Cursor::new(Vec::new()), &mut state)
+            .unwrap();
+        let previous = state.clone();
+
+        let writer = FailingWriter {
+            inner: Cursor::new(Vec::new()),
+            limit: 1000,
+        };
+        let result = deck(&[("b", "2")]).write_update(writer, &mut state);
+        assert!(result.is_err());
+        assert_eq!(state, previous);
+
+        deck(&[("b", "2")])
+            .write_update(Cursor::new(Vec::new()), &mut state)
+            .unwrap();
+        assert_ne!(state, previous);
+    }
+
+    #[cfg(all(feature = "markdown", feature = "highlight"))]
+    #[test]
+    fn markdown_code_blocks_are_highlighted() {
+        let field = Field::new("Front".to_string())
+            .markdown(true)
+            .code("rs".to_string());
+        let model = Model::new(
+            1,
+            "Basic".to_string(),
+            vec![field],
+            0,
+            String::new(),
+            ModelType::Standard,
+        );
+        let value = model.fields[0].process_value(
+            "Some *code*:\n\n```\nfn a() {}\n```\n\n```py\nx = 1\n```".to_string(),
+            &model,
+        );
+        assert!(value.starts_with("<p>Some <em>code</em>:</p>"), "{value}");
+        assert_eq!(
+            value.matches("<pre class=\"hl-code\">").count(),
+            2,
+            "{value}"
+        );
+        assert!(
+            value.contains("hl-rust") && value.contains("hl-python"),
+            "{value}"
+        );
+    }
+}
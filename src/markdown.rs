@@ -0,0 +1,87 @@
+//! Converting Markdown field values to HTML.
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
+// turns the language and contents of a code block into HTML
+pub(crate) type Highlight<'a> = &'a dyn Fn(&str, &str) -> String;
+
+/// Converts Markdown to HTML. Raw HTML in the Markdown is escaped and displayed as text.
+/// A value that is a single paragraph is not wrapped in `<p>` so that short values like a single word display inline.
+/// Code blocks are turned into HTML with `highlight` if it's given, with the language after the opening fence or an empty language.
+pub(crate) fn to_html(markdown: &str, highlight: Option<Highlight>) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut events = Vec::new();
+    // the language and contents of the code block that is being read, if it's highlighted
+    let mut code_block: Option<(String, String)> = None;
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) if highlight.is_some() => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((language, String::new()));
+            }
+            Event::Text(text) if code_block.is_some() => {
+                if let Some((_language, code)) = &mut code_block {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) if code_block.is_some() => {
+                if let (Some((language, code)), Some(highlight)) = (code_block.take(), highlight) {
+                    events.push(Event::Html(highlight(&language, &code).into()));
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) => events.push(Event::Text(html)),
+            event => events.push(event),
+        }
+    }
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+
+    let html = html.trim_end();
+    match html
+        .strip_prefix("<p>")
+        .and_then(|html| html.strip_suffix("</p>"))
+    {
+        Some(paragraph) if !paragraph.contains("<p>") => paragraph.to_string(),
+        _ => html.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_paragraphs_are_not_wrapped() {
+        assert_eq!(to_html("**a**", None), "<strong>a</strong>");
+        assert_eq!(to_html("a\n\nb", None), "<p>a</p>\n<p>b</p>");
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        assert_eq!(to_html("<b>a</b>", None), "&lt;b&gt;a&lt;/b&gt;");
+    }
+
+    #[test]
+    fn code_blocks_are_highlighted() {
+        let highlight =
+            |language: &str, code: &str| format!("<pre lang=\"{language}\">{code}</pre>");
+        assert_eq!(
+            to_html(
+                "a\n\n```rust ignore\nfn a() {}\n```\n\n    b < c",
+                Some(&highlight)
+            ),
+            "<p>a</p>\n<pre lang=\"rust\">fn a() {}\n</pre><pre lang=\"\">b < c</pre>"
+        );
+        assert_eq!(
+            to_html("```\na < b\n```", None),
+            "<pre><code>a &lt; b\n</code></pre>"
+        );
+    }
+}
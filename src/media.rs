@@ -0,0 +1,633 @@
+// finding the media files that are referred to in fields and CSS and gathering them for the package
+
+use crate::{Deck, Error, Media, WriteOptions, error, html, media_type};
+use sha1::{Digest, Sha1};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
+use unicode_normalization::{IsNormalized, UnicodeNormalization};
+
+// the longest media file name Anki accepts in bytes
+const MAX_NAME_LENGTH: usize = 120;
+
+// the tags and attributes that refer to media files in fields
+const MEDIA_ATTRIBUTES: &[(&str, &str)] = &[
+    ("img", "src"),
+    ("audio", "src"),
+    ("video", "src"),
+    ("source", "src"),
+    ("object", "data"),
+];
+
+/// A summary of the media files in a package, see [`Deck::media_report`].
+#[derive(Debug, Default)]
+pub struct MediaReport {
+    /// The number of media files in the package.
+    pub files: usize,
+    /// The total size of the media files in the package in bytes.
+    pub bytes: u64,
+    /// The media files that were renamed because Anki would change their names when importing them
+    /// or because they were converted into another format, mapped to the name they are stored as.
+    /// A number is added to the name if another file already has it, such as `ab-1.png` for `a:b.png` when there is an `ab.png`.
+    /// References to them are rewritten to use the new name.
+    pub renamed: BTreeMap<String, String>,
+    /// The media files that have the same content as another file, mapped to the name of the file they are stored as.
+    /// References to them are rewritten to refer to the stored file.
+    pub duplicates: BTreeMap<String, String>,
+    /// The number of bytes saved by storing each duplicated file only once.
+    pub saved_bytes: u64,
+}
+
+// the media files to write into the package, and the names references to them have to be rewritten to
+#[derive(Debug)]
+pub(crate) struct MediaFiles<'a> {
+    pub(crate) files: Vec<Cow<'a, Media>>,
+    normalize_names: bool,
+    // name => normalised name
+    renamed: BTreeMap<String, String>,
+    // normalised name => name of the file with the same content that is stored
+    duplicates: BTreeMap<String, String>,
+    saved_bytes: u64,
+}
+
+impl MediaFiles<'_> {
+    // rewrites the references in the field value to renamed media files
+    pub(crate) fn rewrite_field<'b>(&self, value: &'b str) -> Cow<'b, str> {
+        self.rewrite(value, field_references(value))
+    }
+
+    // rewrites the references in the CSS to renamed media files
+    pub(crate) fn rewrite_css<'b>(&self, css: &'b str) -> Cow<'b, str> {
+        self.rewrite(css, css_references(css))
+    }
+
+    // the references are slices of the text, so their position in it can be found from their addresses
+    fn rewrite<'b>(&self, text: &'b str, references: Vec<&str>) -> Cow<'b, str> {
+        let mut rewrites = references
+            .into_iter()
+            .filter_map(|reference| Some((reference, self.target(reference)?)))
+            .collect::<Vec<_>>();
+        if rewrites.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        rewrites.sort_by_key(|(reference, _target)| reference.as_ptr());
+
+        let mut rewritten = String::with_capacity(text.len());
+        let mut last = 0;
+        for (reference, target) in rewrites {
+            let start = reference.as_ptr() as usize - text.as_ptr() as usize;
+            rewritten.push_str(&text[last..start]);
+            rewritten.push_str(&target);
+            last = start + reference.len();
+        }
+        rewritten.push_str(&text[last..]);
+        Cow::Owned(rewritten)
+    }
+
+    // the name the reference should be rewritten to, if it needs to be
+    fn target<'b>(&'b self, reference: &'b str) -> Option<Cow<'b, str>> {
+        let name = match self.renamed.get(reference) {
+            Some(renamed) => Cow::Borrowed(renamed.as_str()),
+            // references to files that weren't added still get renamed by Anki
+            None if self.normalize_names => normalize_name(reference),
+            None => Cow::Borrowed(reference),
+        };
+        let name = match self.duplicates.get(name.as_ref()) {
+            Some(stored) => Cow::Borrowed(stored.as_str()),
+            None => name,
+        };
+        (name != reference).then_some(name)
+    }
+
+    pub(crate) fn report(&self) -> MediaReport {
+        MediaReport {
+            files: self.files.len(),
+            bytes: self.files.iter().map(|m| m.data.len() as u64).sum(),
+            renamed: self.renamed.clone(),
+            duplicates: self.duplicates.clone(),
+            saved_bytes: self.saved_bytes,
+        }
+    }
+}
+
+// the media files to write into the package, in the order they were added followed by the discovered ones
+// the files are checked, the names are normalised like Anki does and files with the same content are only stored once, under the name of the first one
+// files starting with an underscore, such as model assets, are kept as they are, since templates may refer to them in ways that can't be rewritten
+pub(crate) fn collect<'a>(
+    decks: &[&'a Deck],
+    options: &WriteOptions,
+) -> Result<MediaFiles<'a>, Error> {
+    let mut media = Vec::new();
+    let mut names = HashSet::new();
+    let mut models = HashSet::new();
+    let assets = decks
+        .iter()
+        .flat_map(|d| d.model_to_templates.values())
+        .filter(|(model, _templates)| models.insert(model.id))
+        .flat_map(|(model, _templates)| &model.assets)
+        .collect::<Vec<_>>();
+    for asset in &assets {
+        if !is_kept_by_anki(&asset.name) {
+            return Err(Error::InvalidMedia {
+                name: asset.name.clone(),
+                reason: "the names of model assets should start with an underscore so that Anki keeps them"
+                    .to_string(),
+            });
+        }
+    }
+    for media_file in decks.iter().flat_map(|d| &d.media).chain(assets) {
+        if names.insert(media_file.name.as_str()) {
+            media.push(Cow::Borrowed(media_file));
+        } else {
+            tracing::warn!(
+                "Media file {:?} was added more than once, only the first one is used",
+                media_file.name
+            );
+        }
+    }
+
+    // references may differ from the names of the files they refer to in ways that are normalised away
+    let mut normalized_names = names
+        .iter()
+        .map(|name| normalize_name(name).into_owned())
+        .collect::<HashSet<_>>();
+    let mut discovered = Vec::new();
+    for deck in decks {
+        let mut resolve = |name: &str| -> Result<(), Error> {
+            if names.contains(name) || normalized_names.contains(normalize_name(name).as_ref()) {
+                return Ok(());
+            }
+            match find(deck.media_root.as_deref(), name)? {
+                Some(data) => {
+                    normalized_names.insert(normalize_name(name).into_owned());
+                    discovered.push(Media {
+                        name: name.to_string(),
+                        data,
+                    });
+                }
+                None if options.require_media => {
+                    return Err(Error::MissingMedia {
+                        name: name.to_string(),
+                    });
+                }
+                None => {
+                    tracing::warn!(
+                        "Media file {name:?} referred to in deck {:?} was not found",
+                        deck.name
+                    );
+                }
+            }
+            Ok(())
+        };
+
+        for (model, _templates) in deck.model_to_templates.values() {
+            for name in css_references(&model.full_css()) {
+                resolve(name)?;
+            }
+        }
+        for (index, note) in deck.notes.iter().enumerate() {
+            for value in &note.field_values {
+                for name in field_references(value) {
+                    resolve(name).map_err(|err| err.in_note(deck, index, note))?;
+                }
+            }
+        }
+    }
+    media.extend(discovered.into_iter().map(Cow::Owned));
+
+    let mut files = Vec::with_capacity(media.len());
+    let mut renamed = BTreeMap::new();
+    let mut duplicates = BTreeMap::new();
+    let mut saved_bytes = 0;
+    // content hash => name of the stored file
+    let mut stored = HashMap::new();
+    // files whose names are already normalised keep them, and renamed files need a name that no other file has
+    let mut taken_names = media
+        .iter()
+        .filter(|m| matches!(normalize_name(&m.name), Cow::Borrowed(_)))
+        .map(|m| m.name.clone())
+        .collect::<HashSet<_>>();
+    for mut media_file in media {
+        if options.check_media {
+            media_type::check(&media_file)?;
+        }
+        let name = media_file.name.clone();
+        let is_kept = is_kept_by_anki(&name);
+        if is_kept {
+            if let Cow::Owned(normalized) = normalize_name(&name) {
+                return Err(Error::InvalidMediaName { name, normalized });
+            }
+        }
+        #[cfg(feature = "images")]
+        if let (Some(image_processing), false) = (&options.image_processing, is_kept) {
+            if let Some(processed) = image_processing.process(&media_file)? {
+                media_file = Cow::Owned(processed);
+            }
+        }
+        let normalized = normalize_name(&media_file.name);
+        if let Cow::Owned(normalized) = &normalized {
+            if !options.normalize_media_names {
+                return Err(Error::InvalidMediaName {
+                    name: media_file.name.clone(),
+                    normalized: normalized.clone(),
+                });
+            }
+        }
+        if media_file.name != name || matches!(normalized, Cow::Owned(_)) {
+            let unique = unique_name(&normalized, &taken_names);
+            tracing::debug!("Renaming media file {name:?} to {unique:?}");
+            taken_names.insert(unique.clone());
+            media_file.to_mut().name = unique;
+        }
+        if media_file.name != name {
+            renamed.insert(name, media_file.name.clone());
+        }
+
+        let hash = Sha1::digest(&media_file.data);
+        match stored.get(&hash) {
+            Some(canonical) if !is_kept => {
+                tracing::debug!(
+                    "Media file {:?} has the same content as {canonical:?}",
+                    media_file.name
+                );
+                saved_bytes += media_file.data.len() as u64;
+                duplicates.insert(media_file.name.clone(), String::clone(canonical));
+            }
+            _ => {
+                stored
+                    .entry(hash)
+                    .or_insert_with(|| media_file.name.clone());
+                files.push(media_file);
+            }
+        }
+    }
+    Ok(MediaFiles {
+        files,
+        normalize_names: options.normalize_media_names,
+        renamed,
+        duplicates,
+        saved_bytes,
+    })
+}
+
+// whether Anki keeps the file even if no field refers to it
+fn is_kept_by_anki(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+// the normalised name with a number added before the extension if it's taken, e.g. "a-1.jpg" if "a.jpg" is taken
+// the rest of the name is shortened if needed so that the number isn't truncated away
+fn unique_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{extension}")),
+        None => (name, String::new()),
+    };
+    (1..)
+        .map(|number| {
+            let suffix = format!("-{number}{extension}");
+            let max_stem = MAX_NAME_LENGTH.saturating_sub(suffix.len());
+            let end = stem
+                .char_indices()
+                .map(|(i, c)| i + c.len_utf8())
+                .take_while(|end| *end <= max_stem)
+                .last()
+                .unwrap_or_default();
+            normalize_name(&format!("{}{suffix}", &stem[..end])).into_owned()
+        })
+        .find(|unique| !taken.contains(unique))
+        .unwrap_or_else(|| name.to_string())
+}
+
+// the names of the local media files referred to in the field value
+pub(crate) fn field_references(value: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        for (tag_name, attribute) in MEDIA_ATTRIBUTES {
+            if html::tag_name(tag).eq_ignore_ascii_case(tag_name) {
+                references.extend(html::attribute(tag, attribute));
+            }
+        }
+    }
+
+    let mut rest = value;
+    while let Some(start) = rest.find("[sound:") {
+        rest = &rest[start + "[sound:".len()..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        references.push(&rest[..end]);
+        rest = &rest[end + 1..];
+    }
+
+    references.retain(|name| is_local(name));
+    references
+}
+
+// the names of the local files referred to with url(...) in the CSS, such as fonts and background images
+pub(crate) fn css_references(css: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        rest = &rest[start + "url(".len()..];
+        let Some(end) = rest.find(')') else {
+            break;
+        };
+        let url = rest[..end].trim().trim_matches(['"', '\'']);
+        if is_local(url) {
+            references.push(url);
+        }
+        rest = &rest[end + 1..];
+    }
+    references
+}
+
+// whether the reference is to a file in the media folder instead of a web address or inline data
+fn is_local(reference: &str) -> bool {
+    !reference.is_empty()
+        && !reference.contains("://")
+        && !reference.starts_with("//")
+        && !reference.starts_with("data:")
+}
+
+// reads the media file from the media root if it's there
+fn find(root: Option<&Path>, name: &str) -> Result<Option<Vec<u8>>, Error> {
+    let Some(path) = root_path(root, name) else {
+        return Ok(None);
+    };
+    tracing::debug!("Adding media file {}", path.display());
+    std::fs::read(&path)
+        .map(Some)
+        .map_err(error!(Error::Io, "Failed to read media file"))
+}
+
+// the path of the file in the media root that the name refers to, if there is one
+fn root_path(root: Option<&Path>, name: &str) -> Option<PathBuf> {
+    let root = root?;
+    // only files directly in the media root can be referred to, like in Anki's media folder
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return None;
+    }
+    let path = root.join(name);
+    path.is_file().then_some(path)
+}
+
+// whether writing the deck would include a media file with the name, from the same sources as `collect`
+pub(crate) fn is_available(deck: &Deck, name: &str) -> bool {
+    let normalized = normalize_name(name);
+    deck.media
+        .iter()
+        .chain(
+            deck.model_to_templates
+                .values()
+                .flat_map(|(model, _templates)| &model.assets),
+        )
+        .any(|m| m.name == name || normalize_name(&m.name) == normalized)
+        || root_path(deck.media_root.as_deref(), name).is_some()
+}
+
+// normalises the media file name the way Anki does when it imports the file, so that references to it keep working
+pub(crate) fn normalize_name(name: &str) -> Cow<'_, str> {
+    let mut normalized = match unicode_normalization::is_nfc_quick(name.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(name),
+        _ => Cow::Owned(name.nfc().collect::<String>()),
+    };
+    if normalized.contains(is_disallowed) {
+        normalized = Cow::Owned(normalized.replace(is_disallowed, ""));
+    }
+    if matches!(normalized.as_ref(), "." | "..") {
+        normalized = Cow::Owned(format!("{normalized}_"));
+    }
+    if let Some(device_name_len) = windows_device_name_len(&normalized) {
+        let (device_name, rest) = normalized.split_at(device_name_len);
+        normalized = Cow::Owned(format!("{device_name}_{rest}"));
+    }
+    if normalized.ends_with(is_windows_trailing_char) {
+        normalized = Cow::Owned(format!("{normalized}_"));
+    }
+    if normalized.len() > MAX_NAME_LENGTH {
+        normalized = Cow::Owned(truncate_name(&normalized));
+    }
+    normalized
+}
+
+// characters that Anki removes from media file names because they're not allowed on some platforms or in fields
+fn is_disallowed(c: char) -> bool {
+    matches!(
+        c,
+        '[' | ']' | '<' | '>' | ':' | '"' | '/' | '?' | '*' | '^' | '\\' | '|'
+    ) || c.is_ascii_control()
+}
+
+// the length of the device name that the name starts with, for names like CON or nul.txt which are reserved on Windows
+fn windows_device_name_len(name: &str) -> Option<usize> {
+    let stem = name.split('.').next().unwrap_or_default();
+    let is_device_name = matches!(
+        stem.to_ascii_uppercase().as_bytes(),
+        b"CON"
+            | b"PRN"
+            | b"AUX"
+            | b"NUL"
+            | [b'C', b'O', b'M', b'1'..=b'9']
+            | [b'L', b'P', b'T', b'1'..=b'9']
+    );
+    is_device_name.then_some(stem.len())
+}
+
+// characters that names can't end with on Windows
+fn is_windows_trailing_char(c: char) -> bool {
+    c == '.' || c.is_whitespace()
+}
+
+// shortens the name to the maximum length, keeping the extension
+fn truncate_name(name: &str) -> String {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let extension = truncate_to_char_boundary(extension, 10);
+    // leave room for the dot and a trailing underscore
+    let stem = truncate_to_char_boundary(stem, MAX_NAME_LENGTH - extension.len() - 2);
+    let mut truncated = if extension.is_empty() {
+        stem.to_string()
+    } else {
+        format!("{stem}.{extension}")
+    };
+    if truncated.ends_with(is_windows_trailing_char) {
+        truncated.push('_');
+    }
+    truncated
+}
+
+fn truncate_to_char_boundary(s: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_names_like_anki() {
+        // the cases from Anki's own media file name tests
+        assert!(matches!(
+            normalize_name("foo.jpg"),
+            Cow::Borrowed("foo.jpg")
+        ));
+        assert_eq!(normalize_name("con.jpg[]><:\"/?*^\\|\0\r\n"), "con_.jpg");
+        assert_eq!(
+            normalize_name(&format!("{}.jpg", "x".repeat(MAX_NAME_LENGTH * 2))),
+            format!("{}.jpg", "x".repeat(MAX_NAME_LENGTH - ".jpg".len() - 1))
+        );
+
+        let cases = [
+            (".", "._"),
+            ("..", ".._"),
+            ("CON", "CON_"),
+            ("nul.txt", "nul_.txt"),
+            ("com1.mp3", "com1_.mp3"),
+            ("LPT9.tar.gz", "LPT9_.tar.gz"),
+            ("com0.mp3", "com0.mp3"),
+            ("console.png", "console.png"),
+            ("a.", "a._"),
+            ("a ", "a _"),
+            ("a\u{3000}", "a\u{3000}_"),
+            ("e\u{301}.jpg", "\u{e9}.jpg"),
+            ("a:b.png", "ab.png"),
+            ("\u{e9}\u{301}", "\u{e9}\u{301}"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(normalize_name(name), expected, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn finds_local_references_in_fields_and_css() {
+        let value = r#"[sound:a.mp3]<IMG class="x" src='b.png'><img src="https://x/c.png"><audio src=d.ogg>[sound:e.mp3"#;
+        assert_eq!(field_references(value), ["b.png", "d.ogg", "a.mp3"]);
+        let css = r#"@font-face { src: url("_f.woff2") } div { background: url( g.png ), url(data:image/png;base64,x), url(//h/i.png) }"#;
+        assert_eq!(css_references(css), ["_f.woff2", "g.png"]);
+    }
+
+    #[test]
+    fn references_to_duplicated_and_renamed_files_are_rewritten() {
+        let png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut deck = Deck::new(1, "Deck".to_string(), String::new());
+        deck.add_media("a.png".to_string(), png.clone());
+        deck.add_media("b.png".to_string(), png.clone());
+        deck.add_media("con.png".to_string(), [png, vec![0]].concat());
+        let media = collect(&[&deck], &WriteOptions::default()).unwrap();
+
+        let names = media
+            .files
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.png", "con_.png"]);
+        let report = media.report();
+        assert_eq!(report.duplicates["b.png"], "a.png");
+        assert_eq!(report.renamed["con.png"], "con_.png");
+        assert_eq!(report.saved_bytes, 8);
+
+        // the sound tag comes first in the text but is found after the HTML tags, and the replacements differ in length
+        assert_eq!(
+            media.rewrite_field(
+                r#"[sound:b.png] <img src="con.png"> <img src="a.png"> <img src=b.png>"#
+            ),
+            r#"[sound:a.png] <img src="con_.png"> <img src="a.png"> <img src=a.png>"#
+        );
+        assert!(matches!(
+            media.rewrite_field(r#"<img src="a.png">"#),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            media.rewrite_css("div { background: url('b.png') url(con.png) }"),
+            "div { background: url('a.png') url(con_.png) }"
+        );
+    }
+
+    #[test]
+    fn files_that_normalise_to_the_same_name_are_kept_apart() {
+        let png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut deck = Deck::new(1, "Deck".to_string(), String::new());
+        deck.add_media("a:b.png".to_string(), [png.clone(), vec![1]].concat());
+        deck.add_media("ab.png".to_string(), [png.clone(), vec![2]].concat());
+        deck.add_media("a?b.png".to_string(), [png, vec![3]].concat());
+        let media = collect(&[&deck], &WriteOptions::default()).unwrap();
+
+        let files = media
+            .files
+            .iter()
+            .map(|m| (m.name.as_str(), *m.data.last().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(files, [("ab-1.png", 1), ("ab.png", 2), ("ab-2.png", 3)]);
+        assert_eq!(
+            media.rewrite_field(r#"<img src="a:b.png"><img src="ab.png"><img src="a?b.png">"#),
+            r#"<img src="ab-1.png"><img src="ab.png"><img src="ab-2.png">"#
+        );
+    }
+
+    #[test]
+    fn unique_names_stay_within_the_length_limit() {
+        let name = format!("{}.png", "x".repeat(MAX_NAME_LENGTH - ".png".len()));
+        let taken = HashSet::from([name.clone()]);
+        let unique = unique_name(&name, &taken);
+        assert!(unique.len() <= MAX_NAME_LENGTH);
+        assert!(unique.ends_with("x-1.png"), "{unique}");
+    }
+
+    #[test]
+    fn truncation_keeps_the_extension_and_char_boundaries() {
+        let name = format!("{}.{}", "\u{e4}".repeat(100), "x".repeat(20));
+        let truncated = normalize_name(&name);
+        assert!(truncated.len() <= MAX_NAME_LENGTH);
+        assert!(truncated.ends_with(&format!(".{}", "x".repeat(10))));
+
+        let name = format!("{} .png", "x".repeat(200));
+        assert_eq!(
+            normalize_name(&name),
+            format!("{}.png", "x".repeat(MAX_NAME_LENGTH - ".png".len() - 1))
+        );
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn processed_images_do_not_take_the_names_of_other_files() {
+        use crate::images::{ImageEncoding, ImageProcessing, tests::noise_png};
+
+        let mut deck = Deck::new(1, "Deck".to_string(), String::new());
+        deck.add_media("x.png".to_string(), noise_png(64));
+        deck.add_media("x.jpg".to_string(), noise_png(32));
+        deck.add_media("x-1.jpg".to_string(), noise_png(16));
+        deck.add_media("_x.png".to_string(), noise_png(64));
+        let options = WriteOptions::default()
+            .check_media(false)
+            .process_images(ImageProcessing::new(ImageEncoding::Jpeg { quality: 50 }));
+        let media = collect(&[&deck], &options).unwrap();
+        let names = media
+            .files
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["x-2.jpg", "x.jpg", "x-1.jpg", "_x.png"]);
+        assert_eq!(
+            media.rewrite_field(r#"<img src="x.png"><img src="x.jpg">"#),
+            r#"<img src="x-2.jpg"><img src="x.jpg">"#
+        );
+    }
+}
@@ -0,0 +1,223 @@
+// detecting the format of media files from their contents to catch broken or mislabelled files
+
+use crate::{Error, Media};
+
+// the binary media formats that can be recognised by their first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MediaType {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Avif,
+    Bmp,
+    Ico,
+    Tiff,
+    Heic,
+    Mp3,
+    Aac,
+    Ogg,
+    Wav,
+    Flac,
+    Mp4,
+    Webm,
+    Woff,
+    Woff2,
+    Ttf,
+    Otf,
+}
+
+impl MediaType {
+    const ALL: &[Self] = &[
+        Self::Png,
+        Self::Jpeg,
+        Self::Gif,
+        Self::Webp,
+        Self::Avif,
+        Self::Bmp,
+        Self::Ico,
+        Self::Tiff,
+        Self::Heic,
+        Self::Mp3,
+        Self::Aac,
+        Self::Ogg,
+        Self::Wav,
+        Self::Flac,
+        Self::Mp4,
+        Self::Webm,
+        Self::Woff,
+        Self::Woff2,
+        Self::Ttf,
+        Self::Otf,
+    ];
+
+    // detects the format from the first bytes of the data
+    fn detect(data: &[u8]) -> Option<Self> {
+        let media_type = match data {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Self::Png,
+            [0xff, 0xd8, 0xff, ..] => Self::Jpeg,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Self::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, kind @ ..] => match kind.get(..4) {
+                Some(b"WEBP") => Self::Webp,
+                Some(b"WAVE") => Self::Wav,
+                _ => return None,
+            },
+            [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => match brand.get(..4) {
+                Some(b"avif" | b"avis") => Self::Avif,
+                Some(b"heic" | b"heix" | b"hevc" | b"heif" | b"mif1" | b"msf1") => Self::Heic,
+                _ => Self::Mp4,
+            },
+            [b'B', b'M', ..] => Self::Bmp,
+            [0, 0, 1, 0, ..] => Self::Ico,
+            [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => Self::Tiff,
+            [b'I', b'D', b'3', ..] => Self::Mp3,
+            // MPEG audio frames start with 11 set bits, followed by the version and layer
+            [0xff, b, ..] if b & 0xf6 == 0xf0 => Self::Aac,
+            [0xff, b, ..] if b & 0xe0 == 0xe0 && b & 0x06 != 0 => Self::Mp3,
+            [b'O', b'g', b'g', b'S', ..] => Self::Ogg,
+            [b'f', b'L', b'a', b'C', ..] => Self::Flac,
+            [0x1a, 0x45, 0xdf, 0xa3, ..] => Self::Webm,
+            [b'w', b'O', b'F', b'F', ..] => Self::Woff,
+            [b'w', b'O', b'F', b'2', ..] => Self::Woff2,
+            [0, 1, 0, 0, ..] | [b't', b'r', b'u', b'e', ..] => Self::Ttf,
+            [b'O', b'T', b'T', b'O', ..] => Self::Otf,
+            _ => return None,
+        };
+        Some(media_type)
+    }
+
+    // the format of files with the extension
+    fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|t| {
+            t.extensions()
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(extension))
+        })
+    }
+
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Png => &["png", "apng"],
+            Self::Jpeg => &["jpg", "jpeg", "jpe", "jfif"],
+            Self::Gif => &["gif"],
+            Self::Webp => &["webp"],
+            Self::Avif => &["avif"],
+            Self::Bmp => &["bmp"],
+            Self::Ico => &["ico"],
+            Self::Tiff => &["tif", "tiff"],
+            Self::Heic => &["heic", "heif"],
+            Self::Mp3 => &["mp3"],
+            Self::Aac => &["aac"],
+            Self::Ogg => &["ogg", "oga", "ogv", "opus", "spx"],
+            Self::Wav => &["wav"],
+            Self::Flac => &["flac"],
+            Self::Mp4 => &["mp4", "m4a", "m4v", "mov", "3gp"],
+            Self::Webm => &["webm", "mkv", "mka"],
+            Self::Woff => &["woff"],
+            Self::Woff2 => &["woff2"],
+            Self::Ttf => &["ttf"],
+            Self::Otf => &["otf"],
+        }
+    }
+
+    // whether Anki can display or play the format on all platforms
+    fn is_supported(self) -> bool {
+        !matches!(self, Self::Tiff | Self::Heic)
+    }
+}
+
+// checks that the media file is not empty, that its contents match its extension and that Anki can display it
+pub(crate) fn check(media: &Media) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidMedia {
+        name: media.name.clone(),
+        reason,
+    };
+
+    if media.data.is_empty() {
+        return Err(invalid("the file is empty".to_string()));
+    }
+    let detected = MediaType::detect(&media.data);
+    if let Some(detected) = detected.filter(|t| !t.is_supported()) {
+        return Err(invalid(format!(
+            "{detected:?} files can't be displayed by Anki on all platforms"
+        )));
+    }
+
+    let extension = media
+        .name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .unwrap_or_default();
+    // text formats like SVG, CSS or JavaScript have no reliable signature, so files with unknown extensions are not checked
+    let Some(expected) = MediaType::from_extension(extension) else {
+        return Ok(());
+    };
+    match detected {
+        Some(detected) if detected == expected => Ok(()),
+        Some(detected) => Err(invalid(format!(
+            "the file is named like a {expected:?} file but contains a {detected:?} file"
+        ))),
+        None => Err(invalid(format!(
+            "the file is named like a {expected:?} file but its contents are not recognised as one"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media(name: &str, data: &[u8]) -> Media {
+        Media {
+            name: name.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn detects_formats_from_their_signatures() {
+        let cases: &[(&[u8], MediaType)] = &[
+            (b"\x89PNG\r\n\x1a\n", MediaType::Png),
+            (b"\xff\xd8\xff\xe0", MediaType::Jpeg),
+            (b"GIF89a", MediaType::Gif),
+            (b"RIFF\0\0\0\0WEBPVP8 ", MediaType::Webp),
+            (b"RIFF\0\0\0\0WAVEfmt ", MediaType::Wav),
+            (b"\0\0\0\x1cftypavif", MediaType::Avif),
+            (b"\0\0\0\x1cftypheic", MediaType::Heic),
+            (b"\0\0\0\x1cftypisom", MediaType::Mp4),
+            (b"ID3\x04", MediaType::Mp3),
+            (b"\xff\xfb\x90", MediaType::Mp3),
+            (b"\xff\xf1\x50", MediaType::Aac),
+            (b"OggS", MediaType::Ogg),
+            (b"wOF2", MediaType::Woff2),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(MediaType::detect(data), Some(*expected), "{expected:?}");
+        }
+        assert_eq!(MediaType::detect(b"RIFF\0\0\0\0AVI "), None);
+        assert_eq!(MediaType::detect(b"<svg"), None);
+        assert_eq!(MediaType::from_extension("JPEG"), Some(MediaType::Jpeg));
+        assert_eq!(MediaType::from_extension("svg"), None);
+    }
+
+    #[test]
+    fn checks_that_contents_match_the_extension() {
+        assert!(check(&media("a.png", b"\x89PNG\r\n\x1a\n")).is_ok());
+        assert!(check(&media("a.m4a", b"\0\0\0\x1cftypM4A ")).is_ok());
+        // text formats and unknown extensions are not checked
+        assert!(check(&media("a.svg", b"<svg/>")).is_ok());
+        assert!(check(&media("a", b"data")).is_ok());
+        for (name, data) in [
+            ("a.png", &b""[..]),
+            ("a.png", b"\xff\xd8\xff\xe0"),
+            ("a.mp3", b"<html>"),
+            ("a.tif", b"II*\0"),
+            ("a.heic", b"\0\0\0\x1cftypheic"),
+        ] {
+            assert!(
+                matches!(check(&media(name, data)), Err(Error::InvalidMedia { .. })),
+                "{name}"
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Assigning notes to decks with rules instead of adding each note to a deck by hand.
+//!
+//! The same notes can be routed with different rules to build several differently organised packages.
+
+use crate::{Collection, Deck, DeckConfig, Note, html, tags};
+use std::{path::PathBuf, sync::Arc};
+
+// replaced with the matched part of the tag or field in a rule's deck name
+const PLACEHOLDER: &str = "{}";
+
+/// A rule that decides which deck a note goes into, see [`Router`].
+///
+/// Deck names can contain `::` to put the deck under a parent deck, which Anki creates if it doesn't exist.
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Notes with the tag or a tag below it go into the deck.
+    /// `{}` in the deck name is replaced with the rest of the note's tag, so notes tagged `chapter::03`
+    /// go into `Course::Chapter 03` with the tag `chapter` and the deck `Course::Chapter {}`.
+    Tag { tag: String, deck: String },
+    /// Notes with a non-empty value in the field go into the deck.
+    /// `{}` in the deck name is replaced with the field's text.
+    Field { field: String, deck: String },
+    /// Notes whose field's text is the value go into the deck.
+    FieldEquals {
+        field: String,
+        value: String,
+        deck: String,
+    },
+}
+
+impl Rule {
+    // the name of the deck the note goes into if the rule matches it
+    fn deck_for(&self, note: &Note) -> Option<String> {
+        match self {
+            Self::Tag { tag, deck } => note.tags.iter().flatten().find_map(|note_tag| {
+                if !tags::is_within(note_tag, tag) {
+                    return None;
+                }
+                // the tags are compared case-insensitively, so the rest is found by levels instead of by length
+                let rest = tags::levels(note_tag)
+                    .skip(tags::levels(tag).count())
+                    .collect::<Vec<_>>()
+                    .join(tags::SEPARATOR);
+                fill(deck, &rest)
+            }),
+            Self::Field { field, deck } => {
+                let text = field_text(note, field)?;
+                fill(deck, &text)
+            }
+            Self::FieldEquals { field, value, deck } => {
+                (field_text(note, field)? == *value).then(|| deck.clone())
+            }
+        }
+    }
+}
+
+/// Routes notes into decks by the first [`Rule`] that matches them, or into the default deck if none do.
+#[derive(Debug, Clone)]
+pub struct Router {
+    namespace: String,
+    default_deck: String,
+    rules: Vec<Rule>,
+    config: Option<Arc<DeckConfig>>,
+    media_root: Option<PathBuf>,
+}
+
+impl Router {
+    /// Creates a new router without rules. The decks get ids derived from the namespace and their names, see [`Deck::from_name`].
+    pub fn new(namespace: String, default_deck: String) -> Self {
+        Self {
+            namespace,
+            default_deck,
+            rules: Vec::new(),
+            config: None,
+            media_root: None,
+        }
+    }
+
+    /// Add a rule, which is checked after the rules that were added before it.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Set the options group of the created decks, see [`Deck::config`].
+    pub fn config(mut self, config: Arc<DeckConfig>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Set the media root of the created decks, see [`Deck::media_root`].
+    pub fn media_root(mut self, media_root: PathBuf) -> Self {
+        self.media_root = Some(media_root);
+        self
+    }
+
+    /// The name of the deck the note is routed into.
+    pub fn deck_for(&self, note: &Note) -> String {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.deck_for(note))
+            .unwrap_or_else(|| self.default_deck.clone())
+    }
+
+    /// Routes the notes into decks and collects the decks in the order they were first used.
+    pub fn route<'a, I: IntoIterator<Item = &'a Note>>(&self, notes: I) -> Collection {
+        let mut decks = Vec::<Deck>::new();
+        for note in notes {
+            let name = self.deck_for(note);
+            let index = match decks.iter().position(|d| d.name == name) {
+                Some(index) => index,
+                None => {
+                    tracing::debug!("Creating deck {name:?}");
+                    let mut deck = Deck::from_name(&self.namespace, name, String::new());
+                    deck.config = self.config.clone();
+                    deck.media_root = self.media_root.clone();
+                    decks.push(deck);
+                    decks.len() - 1
+                }
+            };
+            decks[index].add_note(note.clone());
+        }
+        Collection {
+            decks,
+            ..Collection::default()
+        }
+    }
+}
+
+// the deck name with the placeholder replaced, or None if there's nothing to replace it with
+fn fill(deck: &str, value: &str) -> Option<String> {
+    if !deck.contains(PLACEHOLDER) {
+        return Some(deck.to_string());
+    }
+    let value = value.trim();
+    (!value.is_empty()).then(|| deck.replace(PLACEHOLDER, value))
+}
+
+// the text of the note's field, without HTML
+fn field_text(note: &Note, field: &str) -> Option<String> {
+    let index = note.model.fields.iter().position(|f| f.name == field)?;
+    let text = html::strip_html(note.field_values.get(index)?);
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Field, Model, ModelType, Template};
+
+    fn make_notes(notes: &[(&str, &str, &[&str])]) -> Vec<Note> {
+        let model = Arc::new(Model::new(
+            1,
+            "Basic".to_string(),
+            vec![
+                Field::new("Front".to_string()),
+                Field::new("Source".to_string()),
+            ],
+            0,
+            String::new(),
+            ModelType::Standard,
+        ));
+        let template = Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            "{{Front}}".to_string(),
+            "{{Source}}".to_string(),
+        ));
+        notes
+            .iter()
+            .map(|(guid, source, tags)| {
+                Note::new(
+                    guid.to_string(),
+                    model.clone(),
+                    vec![template.clone()],
+                    vec![guid.to_string(), source.to_string()],
+                )
+                .tags(tags.iter().map(|t| t.to_string()).collect())
+            })
+            .collect()
+    }
+
+    fn tag_rule(tag: &str, deck: &str) -> Rule {
+        Rule::Tag {
+            tag: tag.to_string(),
+            deck: deck.to_string(),
+        }
+    }
+
+    #[test]
+    fn routes_by_the_rest_of_the_tag() {
+        let router = Router::new("org".to_string(), "Course".to_string())
+            .rule(tag_rule("chapter", "Course::Chapter {}"));
+        let notes = make_notes(&[
+            ("a", "", &["chapter::03"]),
+            ("b", "", &["Chapter::04::Review"]),
+            ("c", "", &["chapter"]),
+            ("d", "", &["chapters::05"]),
+        ]);
+        let decks = notes
+            .iter()
+            .map(|note| router.deck_for(note))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decks,
+            [
+                "Course::Chapter 03",
+                "Course::Chapter 04::Review",
+                "Course",
+                "Course"
+            ]
+        );
+
+        // the lowercase rule tag is shorter in bytes than the note's tag
+        let router = Router::new("org".to_string(), "Default".to_string())
+            .rule(tag_rule("\u{1e9e}", "Deck {}"));
+        let note = &make_notes(&[("a", "", &["\u{df}::x"])])[0];
+        assert_eq!(router.deck_for(note), "Deck x");
+    }
+
+    #[test]
+    fn routes_by_fields() {
+        let router = Router::new("org".to_string(), "Default".to_string())
+            .rule(Rule::FieldEquals {
+                field: "Source".to_string(),
+                value: "Textbook".to_string(),
+                deck: "Textbook".to_string(),
+            })
+            .rule(Rule::Field {
+                field: "Source".to_string(),
+                deck: "Sources::{}".to_string(),
+            })
+            .rule(Rule::Field {
+                field: "Missing".to_string(),
+                deck: "Missing".to_string(),
+            });
+        let notes = make_notes(&[
+            ("a", "<b>Textbook</b>", &[]),
+            ("b", " Lecture 2 ", &[]),
+            ("c", "<br>", &[]),
+        ]);
+        let decks = notes
+            .iter()
+            .map(|note| router.deck_for(note))
+            .collect::<Vec<_>>();
+        assert_eq!(decks, ["Textbook", "Sources::Lecture 2", "Default"]);
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let router = Router::new("org".to_string(), "Default".to_string())
+            .rule(tag_rule("hard", "Hard"))
+            .rule(tag_rule("chapter", "Chapter {}"));
+        let notes = make_notes(&[
+            ("a", "", &["chapter::1", "hard"]),
+            ("b", "", &["chapter::1"]),
+            ("c", "", &["chapter::2"]),
+            ("d", "", &[]),
+        ]);
+        let collection = router.route(&notes);
+        let decks = collection
+            .decks
+            .iter()
+            .map(|deck| {
+                let guids = deck
+                    .notes
+                    .iter()
+                    .map(|n| n.guid.as_str())
+                    .collect::<Vec<_>>();
+                (deck.name.as_str(), guids)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decks,
+            [
+                ("Hard", vec!["a"]),
+                ("Chapter 1", vec!["b"]),
+                ("Chapter 2", vec!["c"]),
+                ("Default", vec!["d"]),
+            ]
+        );
+        assert_eq!(collection.decks[0].id, crate::id::deck_id("org", "Hard"));
+    }
+}
@@ -64,7 +64,7 @@ diesel::table! {
         usn -> BigInt,
         tags -> Text,
         flds -> Text,
-        sfld -> BigInt,
+        sfld -> Text,
         csum -> BigInt,
         flags -> BigInt,
         data -> Text,
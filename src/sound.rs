@@ -0,0 +1,233 @@
+//! Referring to sound files in fields and reading fields aloud with Anki's text-to-speech.
+//!
+//! Anki ignores malformed sound tags and TTS settings without an error, so these helpers check them when they are created.
+
+use crate::{Deck, Error, media};
+
+/// Creates a `[sound:name]` tag for a field, which plays the sound file when the card is shown.
+/// The file should have been added to the deck with [`Deck::add_media`], be a model asset or be in the deck's media root.
+pub fn tag(deck: &Deck, name: &str) -> Result<String, Error> {
+    if name.is_empty() || name.contains(['[', ']']) {
+        return Err(invalid(
+            name,
+            "sound file names should not be empty or contain brackets",
+        ));
+    }
+    if !media::is_available(deck, name) {
+        return Err(Error::MissingMedia {
+            name: name.to_string(),
+        });
+    }
+    Ok(format!("[sound:{name}]"))
+}
+
+/// Text-to-speech settings for reading text aloud with the voices available on the device.
+#[derive(Debug, Clone)]
+pub struct Tts {
+    lang: String,
+    voices: Vec<String>,
+    speed: Option<f64>,
+}
+
+impl Tts {
+    /// Creates new TTS settings for the language, such as "en_US" or "ja_JP".
+    /// The device's default voice for the language is used.
+    pub fn new(lang: String) -> Self {
+        Self {
+            lang,
+            voices: Vec::new(),
+            speed: None,
+        }
+    }
+
+    /// Set the preferred voices, the first one that is available on the device is used.
+    pub fn voices(mut self, voices: Vec<String>) -> Self {
+        self.voices = voices;
+        self
+    }
+
+    /// Set the speed relative to the voice's normal speed, e.g. 0.8 to speak slower.
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
+    /// Creates a template replacement like `{{tts en_US voices=Apple_Samantha:Front}}` that reads the field aloud.
+    pub fn template(&self, field: &str) -> Result<String, Error> {
+        if field.is_empty() || field.contains([':', '{', '}']) {
+            return Err(invalid(
+                field,
+                "field names should not be empty or contain ':' or braces",
+            ));
+        }
+        Ok(format!("{{{{tts {}:{field}}}}}", self.options()?))
+    }
+
+    /// Creates a `[anki:tts]` tag that reads the text aloud when it is in a field.
+    pub fn inline(&self, text: &str) -> Result<String, Error> {
+        if text.contains("[/anki:tts]") {
+            return Err(invalid(
+                text,
+                "the text should not contain a closing tts tag",
+            ));
+        }
+        Ok(format!(
+            "[anki:tts lang={}]{text}[/anki:tts]",
+            self.options()?
+        ))
+    }
+
+    // the language followed by the other options, separated by spaces
+    fn options(&self) -> Result<String, Error> {
+        if !is_valid_language(&self.lang) {
+            return Err(invalid(
+                &self.lang,
+                "languages should be written like en or en_US",
+            ));
+        }
+        let mut options = self.lang.clone();
+        if !self.voices.is_empty() {
+            if let Some(voice) = self
+                .voices
+                .iter()
+                .find(|v| v.is_empty() || v.contains([' ', ',', ':', '{', '}', ']']))
+            {
+                return Err(invalid(
+                    voice,
+                    "voice names should not be empty or contain spaces, ',', ':', braces or ']'",
+                ));
+            }
+            options.push_str(" voices=");
+            options.push_str(&self.voices.join(","));
+        }
+        if let Some(speed) = self.speed {
+            if !(speed.is_finite() && speed > 0.0) {
+                return Err(invalid(
+                    &speed.to_string(),
+                    "the speed should be a positive number",
+                ));
+            }
+            options.push_str(&format!(" speed={speed}"));
+        }
+        Ok(options)
+    }
+}
+
+// checks that the language is a language code optionally followed by a region, like en or en_US
+fn is_valid_language(lang: &str) -> bool {
+    let (language, region) = match lang.split_once('_') {
+        Some((language, region)) => (language, Some(region)),
+        None => (lang, None),
+    };
+    let language_valid =
+        (2..=3).contains(&language.len()) && language.bytes().all(|b| b.is_ascii_lowercase());
+    let region_valid = region.is_none_or(|region| {
+        (region.len() == 2 && region.bytes().all(|b| b.is_ascii_uppercase()))
+            || (region.len() == 3 && region.bytes().all(|b| b.is_ascii_digit()))
+    });
+    language_valid && region_valid
+}
+
+fn invalid(value: &str, reason: &'static str) -> Error {
+    Error::InvalidSound {
+        value: value.to_string(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Field, Model, ModelType, Note, Template};
+    use std::sync::Arc;
+
+    fn deck_with_model(model: Model) -> Deck {
+        let model = Arc::new(model);
+        let template = Arc::new(Template::new(
+            2,
+            "Card 1".to_string(),
+            "{{Front}}".to_string(),
+            "{{Front}}".to_string(),
+        ));
+        let mut deck = Deck::new(3, "Deck".to_string(), String::new());
+        deck.add_note(Note::new(
+            "guid".to_string(),
+            model,
+            vec![template],
+            vec!["front".to_string()],
+        ));
+        deck
+    }
+
+    fn model() -> Model {
+        Model::new(
+            1,
+            "Basic".to_string(),
+            vec![Field::new("Front".to_string())],
+            0,
+            String::new(),
+            ModelType::Standard,
+        )
+    }
+
+    #[test]
+    fn tags_refer_to_media_from_every_source() {
+        let root = std::env::temp_dir().join(format!("reanki-sound-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("root.mp3"), b"sound").unwrap();
+
+        let mut deck = deck_with_model(model().asset("_asset.mp3".to_string(), b"sound".to_vec()))
+            .media_root(root.clone());
+        deck.add_media("added.mp3".to_string(), b"sound".to_vec());
+        let results = [
+            "added.mp3",
+            "_asset.mp3",
+            "root.mp3",
+            "missing.mp3",
+            "../root.mp3",
+        ]
+        .map(|name| tag(&deck, name).ok());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            results,
+            [
+                Some("[sound:added.mp3]".to_string()),
+                Some("[sound:_asset.mp3]".to_string()),
+                Some("[sound:root.mp3]".to_string()),
+                None,
+                None,
+            ]
+        );
+        assert!(matches!(
+            tag(&deck, "missing.mp3"),
+            Err(Error::MissingMedia { .. })
+        ));
+        assert!(matches!(
+            tag(&deck, "a]b.mp3"),
+            Err(Error::InvalidSound { .. })
+        ));
+    }
+
+    #[test]
+    fn tts_options_are_checked() {
+        let tts = Tts::new("en_US".to_string())
+            .voices(vec!["Apple_Samantha".to_string()])
+            .speed(0.8);
+        assert_eq!(
+            tts.template("Front").unwrap(),
+            "{{tts en_US voices=Apple_Samantha speed=0.8:Front}}"
+        );
+        assert!(tts.template("a:b").is_err());
+        assert!(tts.inline("a[/anki:tts]").is_err());
+        assert!(Tts::new("english".to_string()).template("Front").is_err());
+        assert!(
+            Tts::new("en".to_string())
+                .speed(0.0)
+                .template("Front")
+                .is_err()
+        );
+        assert!(is_valid_language("es_419"));
+        assert!(!is_valid_language("en_us"));
+    }
+}
@@ -0,0 +1,180 @@
+//! Validating note tags and working with hierarchical tags like `Parent::Child`.
+//!
+//! Tags are separated by spaces in Anki, so they can't contain spaces themselves.
+//! Anki shows tags containing `::` as a hierarchy in the browser sidebar.
+
+use crate::Error;
+use std::collections::{HashMap, hash_map::Entry};
+use unicode_normalization::UnicodeNormalization;
+
+/// The separator between the levels of a hierarchical tag.
+pub const SEPARATOR: &str = "::";
+
+/// Normalises the tag the way Anki does when a tag is added: the tag is converted to Unicode NFC,
+/// spaces, double quotes and control characters are removed, each level is trimmed, and empty levels are named "blank".
+pub fn normalize(tag: &str) -> Result<String, Error> {
+    let nfc = tag.nfc().collect::<String>();
+    if nfc.replace(is_invalid_char, "").trim().is_empty() {
+        return Err(Error::InvalidTag {
+            tag: tag.to_string(),
+            reason: "tags should not be empty",
+        });
+    }
+    let normalized = nfc
+        .split(SEPARATOR)
+        .map(|level| {
+            let level = level.replace(is_invalid_char, "");
+            match level.trim() {
+                "" => "blank".to_string(),
+                trimmed => trimmed.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(SEPARATOR);
+    Ok(normalized)
+}
+
+/// Checks that the tag is already in the form Anki would normalise it into, see [`normalize`].
+pub fn validate(tag: &str) -> Result<(), Error> {
+    let reason = if tag.is_empty() {
+        "tags should not be empty"
+    } else if tag.contains(is_separator) {
+        "tags should not contain spaces, which separate tags"
+    } else if tag.contains(is_invalid_char) {
+        "tags should not contain double quotes or control characters"
+    } else if levels(tag).any(|level| level.is_empty() || level.trim() != level) {
+        "the levels of a hierarchical tag should not be empty or start or end with whitespace"
+    } else if !unicode_normalization::is_nfc(tag) {
+        "tags should be in Unicode NFC form, otherwise Anki treats them as different from the same tag in NFC"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidTag {
+        tag: tag.to_string(),
+        reason,
+    })
+}
+
+/// Creates a hierarchical tag from its levels, e.g. `["Course", "Chapter_03"]` becomes `Course::Chapter_03`.
+pub fn join<S: AsRef<str>>(levels: &[S]) -> Result<String, Error> {
+    let tag = levels
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(SEPARATOR);
+    validate(&tag)?;
+    Ok(tag)
+}
+
+/// The levels of the tag, e.g. `Course`, `Chapter_03` for `Course::Chapter_03`.
+pub fn levels(tag: &str) -> impl Iterator<Item = &str> {
+    tag.split(SEPARATOR)
+}
+
+/// The parent of the tag, e.g. `Course` for `Course::Chapter_03`, if it has one.
+pub fn parent(tag: &str) -> Option<&str> {
+    tag.rsplit_once(SEPARATOR).map(|(parent, _child)| parent)
+}
+
+/// The ancestors of the tag from the top level down, e.g. `A` and `A::B` for `A::B::C`.
+pub fn ancestors(tag: &str) -> impl Iterator<Item = &str> {
+    tag.match_indices(SEPARATOR).map(|(i, _)| &tag[..i])
+}
+
+/// Whether the tag is the ancestor tag or below it in the hierarchy. Tags are compared case-insensitively like in Anki.
+pub fn is_within(tag: &str, ancestor: &str) -> bool {
+    let tag = tag.to_lowercase();
+    let ancestor = ancestor.to_lowercase();
+    tag == ancestor
+        || tag
+            .strip_prefix(&ancestor)
+            .is_some_and(|rest| rest.starts_with(SEPARATOR))
+}
+
+// the tags Anki registers for the tags of the notes: each tag and its ancestors once, compared case-insensitively,
+// with the spelling of the first occurrence also used for the matching levels of later tags like Anki does
+pub(crate) fn registry<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut registered = Vec::new();
+    // lowercase tag => index in registered
+    let mut seen = HashMap::new();
+    for tag in tags {
+        let mut prefix = String::new();
+        for level in levels(tag) {
+            if !prefix.is_empty() {
+                prefix.push_str(SEPARATOR);
+            }
+            prefix.push_str(level);
+            match seen.entry(prefix.to_lowercase()) {
+                Entry::Occupied(entry) => prefix.clone_from(&registered[*entry.get()]),
+                Entry::Vacant(entry) => {
+                    entry.insert(registered.len());
+                    registered.push(prefix.clone());
+                }
+            }
+        }
+    }
+    registered
+}
+
+// characters that separate tags in Anki
+fn is_separator(c: char) -> bool {
+    c == ' ' || c == '\u{3000}'
+}
+
+// characters that Anki removes from tags
+fn is_invalid_char(c: char) -> bool {
+    is_separator(c) || c == '"' || c.is_ascii_control()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_like_anki() {
+        assert_eq!(normalize("a b\"c").unwrap(), "abc");
+        assert_eq!(normalize(" a :: ::b ").unwrap(), "a::blank::b");
+        assert!(normalize(" \"").is_err());
+        assert_eq!(normalize("cafe\u{301}").unwrap(), "caf\u{e9}");
+    }
+
+    #[test]
+    fn validates_normalized_tags() {
+        assert!(validate("Course::Chapter_03").is_ok());
+        assert!(validate("caf\u{e9}").is_ok());
+        for tag in [
+            "",
+            "a b",
+            "a\u{3000}b",
+            "a\"b",
+            "a\tb",
+            "a::",
+            "::a",
+            "a:: b",
+            "cafe\u{301}",
+        ] {
+            assert!(validate(tag).is_err(), "{tag:?}");
+        }
+        assert_eq!(join(&["a", "b"]).unwrap(), "a::b");
+        assert!(join(&["a", ""]).is_err());
+    }
+
+    #[test]
+    fn walks_the_hierarchy() {
+        assert_eq!(levels("a::b::c").collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(parent("a::b::c"), Some("a::b"));
+        assert_eq!(parent("a"), None);
+        assert_eq!(ancestors("a::b::c").collect::<Vec<_>>(), ["a", "a::b"]);
+        assert!(is_within("A::b", "a"));
+        assert!(is_within("a", "A"));
+        assert!(!is_within("ab", "a"));
+    }
+
+    #[test]
+    fn registers_tags_with_their_ancestors_once() {
+        assert_eq!(
+            registry(["a::b", "A::c", "B", "b", "a::B::c"]),
+            ["a", "a::b", "a::c", "B", "a::b::c"]
+        );
+    }
+}
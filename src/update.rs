@@ -0,0 +1,301 @@
+//! Keeping ids stable between builds of the same deck.
+
+use crate::{Error, Model, error};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{Read, Write},
+};
+
+/// The ids and content checksums of a deck build.
+/// Keeping it between builds lets an updated deck reuse the ids of the previous one,
+/// so that Anki updates the notes users already have instead of duplicating them.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildState {
+    // model name => model id
+    models: BTreeMap<String, i64>,
+    // note guid => note state
+    notes: BTreeMap<String, NoteState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct NoteState {
+    id: i64,
+    #[serde(rename = "mod")]
+    modified: i64,
+    checksum: String,
+    // template id => card id
+    cards: BTreeMap<i64, i64>,
+}
+
+impl BuildState {
+    /// Creates an empty build state for the first build of a deck.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a build state saved with [`BuildState::write`].
+    pub fn read<R: Read>(reader: R) -> Result<Self, Error> {
+        serde_json::from_reader(reader).map_err(error!(Error::Json, "Failed to read build state"))
+    }
+
+    /// Write the build state as JSON.
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), Error> {
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(error!(Error::Json, "Failed to write build state"))
+    }
+}
+
+// a note that was in the previous build but not in this one
+pub(crate) struct DeletedNote {
+    pub(crate) guid: String,
+    pub(crate) id: i64,
+    pub(crate) card_ids: Vec<i64>,
+}
+
+// assigns the note and card ids while writing, reusing the ids from the previous build if there is one
+// the state of this build is only staged, so that the previous state is kept if writing the package fails
+pub(crate) struct Ids<'a> {
+    state: Option<&'a BuildState>,
+    timestamp_secs: i64,
+    next_note_id: i64,
+    next_card_id: i64,
+    used_note_ids: HashSet<i64>,
+    used_card_ids: HashSet<i64>,
+    models: BTreeMap<String, i64>,
+    notes: BTreeMap<String, NoteState>,
+}
+
+impl<'a> Ids<'a> {
+    pub(crate) fn new(
+        state: Option<&'a BuildState>,
+        timestamp_secs: i64,
+        timestamp_millis: i64,
+    ) -> Self {
+        let mut used_note_ids = HashSet::new();
+        let mut used_card_ids = HashSet::new();
+        if let Some(state) = state {
+            for note in state.notes.values() {
+                used_note_ids.insert(note.id);
+                used_card_ids.extend(note.cards.values());
+            }
+        }
+        Self {
+            state,
+            timestamp_secs,
+            next_note_id: timestamp_millis,
+            next_card_id: timestamp_millis,
+            used_note_ids,
+            used_card_ids,
+            models: state.map(|s| s.models.clone()).unwrap_or_default(),
+            notes: BTreeMap::new(),
+        }
+    }
+
+    // checks that the model id has not changed since the previous build, which would give users a new copy of the model
+    pub(crate) fn model(&mut self, model: &Model) -> Result<(), Error> {
+        let Some(state) = self.state else {
+            return Ok(());
+        };
+        match state.models.get(&model.name) {
+            Some(&previous) if previous != model.id => Err(Error::ChangedModelId {
+                name: model.name.clone(),
+                previous,
+                id: model.id,
+            }),
+            _ => {
+                self.models.insert(model.name.clone(), model.id);
+                Ok(())
+            }
+        }
+    }
+
+    // returns the id and modified timestamp for the note
+    pub(crate) fn note(&mut self, guid: &str, fields: &str, tags: &str) -> (i64, i64) {
+        let Some(state) = &self.state else {
+            self.next_note_id += 1;
+            return (self.next_note_id, self.timestamp_secs);
+        };
+
+        let checksum = checksum(fields, tags);
+        let (id, modified) = match state.notes.get(guid) {
+            // only notes whose content changed are marked as modified so that Anki leaves the rest alone
+            Some(previous) if previous.checksum == checksum => (previous.id, previous.modified),
+            Some(previous) => (previous.id, self.timestamp_secs),
+            None => (
+                next_unused(&mut self.next_note_id, &self.used_note_ids),
+                self.timestamp_secs,
+            ),
+        };
+        self.notes.insert(
+            guid.to_string(),
+            NoteState {
+                id,
+                modified,
+                checksum,
+                cards: BTreeMap::new(),
+            },
+        );
+        (id, modified)
+    }
+
+    // returns the id for the note's card for the template
+    pub(crate) fn card(&mut self, guid: &str, template_id: i64) -> i64 {
+        let Some(state) = &self.state else {
+            self.next_card_id += 1;
+            return self.next_card_id;
+        };
+
+        let previous = state
+            .notes
+            .get(guid)
+            .and_then(|n| n.cards.get(&template_id))
+            .copied();
+        let id =
+            previous.unwrap_or_else(|| next_unused(&mut self.next_card_id, &self.used_card_ids));
+        if let Some(note) = self.notes.get_mut(guid) {
+            note.cards.insert(template_id, id);
+        }
+        id
+    }
+
+    // returns the state of this build, which replaces the previous state once the package is written,
+    // and the notes that were deleted since the previous build
+    pub(crate) fn finish(self) -> (Option<BuildState>, Vec<DeletedNote>) {
+        let Some(state) = self.state else {
+            return (None, Vec::new());
+        };
+
+        let deleted = state
+            .notes
+            .iter()
+            .filter(|(guid, _)| !self.notes.contains_key(*guid))
+            .map(|(guid, note)| DeletedNote {
+                guid: guid.clone(),
+                id: note.id,
+                card_ids: note.cards.values().copied().collect(),
+            })
+            .collect();
+        let state = BuildState {
+            models: self.models,
+            notes: self.notes,
+        };
+        (Some(state), deleted)
+    }
+}
+
+fn next_unused(next: &mut i64, used: &HashSet<i64>) -> i64 {
+    *next += 1;
+    while used.contains(next) {
+        *next += 1;
+    }
+    *next
+}
+
+fn checksum(fields: &str, tags: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(fields.as_bytes());
+    hasher.update(b"\x1e");
+    hasher.update(tags.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Field, ModelType};
+
+    fn model(id: i64) -> Model {
+        Model::new(
+            id,
+            "Basic".to_string(),
+            vec![Field::new("Front".to_string())],
+            0,
+            String::new(),
+            ModelType::Standard,
+        )
+    }
+
+    // runs a build with the given notes as (guid, fields) with one card each
+    fn build(
+        state: &BuildState,
+        notes: &[(&str, &str)],
+    ) -> (BuildState, Vec<(i64, i64, i64)>, Vec<i64>) {
+        let mut ids = Ids::new(Some(state), 100, 100_000);
+        ids.model(&model(1)).unwrap();
+        let rows = notes
+            .iter()
+            .map(|(guid, fields)| {
+                let (id, modified) = ids.note(guid, fields, "");
+                (id, modified, ids.card(guid, 7))
+            })
+            .collect();
+        let (new_state, deleted) = ids.finish();
+        let deleted = deleted.iter().map(|n| n.id).collect();
+        (new_state.unwrap(), rows, deleted)
+    }
+
+    #[test]
+    fn ids_are_kept_between_builds() {
+        let (state, first, deleted) = build(&BuildState::new(), &[("a", "1"), ("b", "2")]);
+        assert!(deleted.is_empty());
+
+        let (_, second, deleted) = build(&state, &[("a", "1"), ("b", "changed"), ("c", "3")]);
+        // unchanged notes keep their modified timestamp, changed ones get the new one
+        assert_eq!(second[0], first[0]);
+        assert_eq!(second[1].0, first[1].0);
+        assert_eq!(second[1].2, first[1].2);
+        assert!(deleted.is_empty());
+        // new notes don't reuse the ids of existing notes
+        assert!(
+            !first
+                .iter()
+                .any(|row| row.0 == second[2].0 || row.2 == second[2].2)
+        );
+    }
+
+    #[test]
+    fn removed_notes_are_reported_as_deleted() {
+        let (state, first, _) = build(&BuildState::new(), &[("a", "1"), ("b", "2")]);
+        let (state, _, deleted) = build(&state, &[("a", "1")]);
+        assert_eq!(deleted, vec![first[1].0]);
+        assert!(!state.notes.contains_key("b"));
+    }
+
+    #[test]
+    fn finishing_does_not_change_the_previous_state() {
+        let (state, _, _) = build(&BuildState::new(), &[("a", "1")]);
+        let previous = state.clone();
+        let (new_state, _, _) = build(&state, &[("b", "2")]);
+        assert_eq!(state, previous);
+        assert_ne!(new_state, previous);
+    }
+
+    #[test]
+    fn changed_model_ids_are_rejected() {
+        let (state, _, _) = build(&BuildState::new(), &[("a", "1")]);
+        let mut ids = Ids::new(Some(&state), 100, 100_000);
+        assert!(ids.model(&model(1)).is_ok());
+        assert!(matches!(
+            ids.model(&model(2)),
+            Err(Error::ChangedModelId {
+                previous: 1,
+                id: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn build_state_round_trips_through_json() {
+        let (state, _, _) = build(&BuildState::new(), &[("a", "1")]);
+        let mut json = Vec::new();
+        state.write(&mut json).unwrap();
+        assert_eq!(BuildState::read(json.as_slice()).unwrap(), state);
+    }
+}